@@ -0,0 +1,49 @@
+// Throughput of the wire codec on a typical answer: parsing straight from the receive buffer, and writing into a
+// buffer that's reused for every message
+//
+// Baseline: the deku codec this replaced, as of 686e418, run through the same two benchmarks on the same machine.
+// It serialized into a new buffer every time, since it had no way to reuse one. abec556 can't be compared directly:
+// it resolved compression pointers against the rest of the input rather than the message, so it can't decode MESSAGE.
+//
+//   benchmark        deku (686e418)   this codec
+//   codec/parse      49.3 us          2.1 us
+//   codec/serialize  12.7 us          1.1 us
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use dns::{Question, Response};
+
+/// www.example.com A, answered with a CNAME and an A record, two NS records with glue and an OPT record. Every
+/// name after the question is compressed.
+#[rustfmt::skip]
+const MESSAGE: &[u8] = &[
+    0x12, 0x34, 0x84, 0x00, 0x00, 0x01, 0x00, 0x02, 0x00, 0x02, 0x00, 0x03, // header
+    3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x01, 0x00, 0x01, // question
+    0xC0, 0x0C, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2C, 0x00, 0x06, 3, b'w', b'e', b'b', 0xC0, 0x10, // CNAME web
+    0xC0, 0x2D, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2C, 0x00, 0x04, 93, 184, 216, 34, // A
+    0xC0, 0x10, 0x00, 0x02, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2C, 0x00, 0x06, 3, b'n', b's', b'1', 0xC0, 0x10, // NS ns1
+    0xC0, 0x10, 0x00, 0x02, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2C, 0x00, 0x06, 3, b'n', b's', b'2', 0xC0, 0x10, // NS ns2
+    0xC0, 0x4F, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0E, 0x10, 0x00, 0x04, 192, 0, 2, 53, // ns1 A
+    0xC0, 0x61, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0E, 0x10, 0x00, 0x04, 198, 51, 100, 53, // ns2 A
+    0x00, 0x00, 0x29, 0x04, 0xD0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // OPT
+];
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec");
+    group.throughput(Throughput::Bytes(MESSAGE.len() as u64));
+    group.bench_function("parse", |b| b.iter(|| Question::parse(black_box(MESSAGE)).unwrap()));
+
+    let mut response = Response::from(Question::parse(MESSAGE).unwrap());
+    let mut output = Vec::with_capacity(512);
+    response.serialize_into(&mut output).unwrap();
+    assert_eq!(output, MESSAGE);
+    group.bench_function("serialize", |b| {
+        b.iter(|| {
+            output.clear();
+            black_box(&mut response).serialize_into(&mut output).unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
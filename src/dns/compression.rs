@@ -1,61 +1,109 @@
-use crate::dns::name::DNSName;
-use std::{cell::RefCell, collections::HashMap, ops::Deref, rc::Rc};
+use crate::dns::name::Label;
+use std::sync::OnceLock;
 
-const ENABLED: bool = false;
+/// Compression pointers only have 14 bits for the offset, so names written past this can't be pointed to.
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
 
-#[derive(Debug, PartialEq, Clone)]
-pub struct CompressedRef(Rc<Compressed>);
+/// Set `DNS_DISABLE_COMPRESSION=1` to write every name in full, which makes packet captures easier to read.
+pub(crate) fn enabled_by_default() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| std::env::var_os("DNS_DISABLE_COMPRESSION").is_none_or(|v| v.is_empty() || v == "0"))
+}
 
-impl CompressedRef {
-    pub(crate) fn new(is_tcp: bool) -> Self {
-        CompressedRef(Rc::new(Compressed {
-            pointers: RefCell::new(HashMap::new()),
-            is_tcp,
-        }))
-    }
+/// Suffix table used while writing a single message.
+/// Holds the offset of every name suffix written in full, and compares against the message itself rather than
+/// keeping copies of the names.
+#[derive(Debug)]
+pub(crate) struct Compression {
+    offsets: Vec<u16>,
+    enabled: bool,
 }
 
-impl Deref for CompressedRef {
-    type Target = Compressed;
+impl Compression {
+    pub(crate) fn new() -> Self {
+        Compression {
+            offsets: Vec::new(),
+            enabled: enabled_by_default(),
+        }
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.offsets.clear();
     }
-}
 
-#[derive(Debug)]
-pub struct Compressed {
-    pub(crate) pointers: RefCell<HashMap<DNSName, usize>>,
-    is_tcp: bool,
-}
+    /// Remembers that a suffix is about to be written at `offset`
+    pub(crate) fn add(&mut self, offset: usize) {
+        if self.enabled && offset <= MAX_POINTER_OFFSET {
+            self.offsets.push(offset as u16);
+        }
+    }
 
-impl PartialEq for Compressed {
-    fn eq(&self, _other: &Self) -> bool {
-        false
+    /// Offset of the first copy of `suffix` written to `message`
+    pub(crate) fn find(&self, message: &[u8], suffix: &[Label]) -> Option<u16> {
+        if !self.enabled {
+            return None;
+        }
+        self.offsets
+            .iter()
+            .copied()
+            .find(|&offset| matches_at(message, offset as usize, suffix) == Some(true))
     }
-}
 
-impl Compressed {
-    pub fn clear(&self) {
-        let mut pointers = self.pointers.borrow_mut();
-        pointers.clear();
+    /// Forgets suffixes at or after `len`, once the message has been cut back to that length
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.offsets.retain(|&offset| (offset as usize) < len);
     }
+}
 
-    pub(crate) fn add(&self, name: DNSName, mut offset: usize) {
-        if !ENABLED {
-            return;
-        }
-        if self.is_tcp {
-            offset -= 2;
+/// Whether the name at `offset` in `message` is `suffix`, ignoring ASCII case. `None` if the name ends early, which
+/// happens when it's the one still being written.
+fn matches_at(message: &[u8], mut offset: usize, suffix: &[Label]) -> Option<bool> {
+    for label in suffix {
+        offset = skip_pointers(message, offset)?;
+        let len = *message.get(offset)? as usize;
+        if !message.get(offset + 1..offset + 1 + len)?.eq_ignore_ascii_case(label.as_bytes()) {
+            return Some(false);
         }
-        let offset = 0xC000 | offset;
-        let mut pointers = self.pointers.borrow_mut();
-        pointers.insert(name, offset);
+        offset += 1 + len;
+    }
+    Some(*message.get(skip_pointers(message, offset)?)? == 0)
+}
+
+/// Follows pointers from `offset` to the next label. We only write pointers that go backwards, so this ends.
+fn skip_pointers(message: &[u8], mut offset: usize) -> Option<usize> {
+    while *message.get(offset)? & 0xC0 == 0xC0 {
+        offset = usize::from(u16::from_be_bytes([message[offset] & 0x3F, *message.get(offset + 1)?]));
     }
+    Some(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::{name::DNSName, wire::Writer};
+
+    #[test]
+    fn test_suffix_pointers() {
+        let mut output = Vec::new();
+        let mut writer = Writer::new(&mut output).with_compression(true);
+        DNSName::from_url("www.example.com").write(&mut writer, true).unwrap();
+        DNSName::from_url("mail.EXAMPLE.com").write(&mut writer, true).unwrap();
+        DNSName::from_url("mail.example.com").write(&mut writer, true).unwrap();
+        // A suffix of a name that was itself compressed
+        DNSName::from_url("mail.example.com.x").write(&mut writer, true).unwrap();
+        assert_eq!(
+            writer.message(),
+            b"\x03www\x07example\x03com\x00\x04mail\xC0\x04\xC0\x11\x04mail\x07example\x03com\x01x\x00"
+        );
+
+        // A name can't point into itself before it's finished
+        let start = writer.position();
+        DNSName::from_url("y.y").write(&mut writer, true).unwrap();
+        assert_eq!(&writer.message()[start..], b"\x01y\x01y\x00");
 
-    pub(crate) fn query(&self, name: &DNSName, offset: usize) -> Option<usize> {
-        let name1 = &name[offset..];
-        let pointers = self.pointers.borrow();
-        pointers.get(name1).copied()
+        // Names that were cut off can't be pointed to
+        writer.truncate(17);
+        DNSName::from_url("mail.example.com").write(&mut writer, true).unwrap();
+        assert_eq!(&writer.message()[17..], b"\x04mail\xC0\x04");
     }
 }
@@ -0,0 +1,265 @@
+// DNS Cookies (RFC 7873), with server cookies in the interoperable format of RFC 9018
+
+use crate::dns::edns::{EdnsOption, OPTION_COOKIE};
+use anyhow::anyhow;
+use siphasher::sip::SipHasher24;
+use std::{
+    hash::Hasher,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const CLIENT_COOKIE_LEN: usize = 8;
+const SERVER_COOKIE_LEN: usize = 16;
+const SERVER_COOKIE_VERSION: u8 = 1;
+
+/// RFC 9018 4.3: server cookies are good for an hour, and tolerate clocks up to 5 minutes ahead of ours
+const COOKIE_LIFETIME: u32 = 3600;
+const CLOCK_SKEW: u32 = 300;
+
+/// Cookie option from a query: the client's cookie and the server cookie we gave it last time, if any
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Cookie {
+    pub client: [u8; CLIENT_COOKIE_LEN],
+    pub server: Option<Vec<u8>>,
+}
+
+impl TryFrom<&EdnsOption> for Cookie {
+    type Error = anyhow::Error;
+
+    /// Any length other than a lone client cookie or one followed by an 8 to 32 byte server cookie is malformed,
+    /// and must be answered with FORMERR (RFC 7873 5.2.2)
+    fn try_from(option: &EdnsOption) -> Result<Self, Self::Error> {
+        if option.code != OPTION_COOKIE {
+            return Err(anyhow!("Option {} isn't a cookie", option.code));
+        }
+        let (client, server) = match option.data.len() {
+            CLIENT_COOKIE_LEN => (option.data.as_slice(), None),
+            16..=40 => (&option.data[..CLIENT_COOKIE_LEN], Some(option.data[CLIENT_COOKIE_LEN..].to_vec())),
+            len => return Err(anyhow!("Cookie option of {} bytes is malformed", len)),
+        };
+        Ok(Cookie {
+            client: client.try_into()?,
+            server,
+        })
+    }
+}
+
+impl From<&Cookie> for EdnsOption {
+    fn from(cookie: &Cookie) -> Self {
+        let mut data = cookie.client.to_vec();
+        data.extend(cookie.server.iter().flatten());
+        EdnsOption::new(OPTION_COOKIE, data)
+    }
+}
+
+struct Secrets {
+    current: [u8; 16],
+    /// Cookies handed out just before a rotation stay valid until they'd expire anyway
+    previous: [u8; 16],
+    rotated_at: Instant,
+}
+
+/// Creates and checks server cookies. The SipHash secret is random and rotated every `rotate_every`, so nothing
+/// needs to be configured or shared between restarts.
+pub struct ServerCookies {
+    secrets: Mutex<Secrets>,
+    rotate_every: Duration,
+    required_above: Option<u16>,
+}
+
+impl Secrets {
+    fn rotate(&mut self) {
+        self.previous = self.current;
+        self.current = random_secret();
+        self.rotated_at = Instant::now();
+    }
+}
+
+fn random_secret() -> [u8; 16] {
+    let mut secret = [0; 16];
+    getrandom::getrandom(&mut secret).expect("No random source for the cookie secret");
+    secret
+}
+
+fn unix_time() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as u32)
+}
+
+impl ServerCookies {
+    pub fn new() -> Self {
+        let secret = random_secret();
+        ServerCookies {
+            secrets: Mutex::new(Secrets {
+                current: secret,
+                previous: secret,
+                rotated_at: Instant::now(),
+            }),
+            rotate_every: Duration::from_secs(COOKIE_LIFETIME as u64),
+            required_above: None,
+        }
+    }
+
+    /// Only send UDP answers larger than `len` bytes to clients that came back with a valid server cookie. Anyone
+    /// else gets them truncated, so a spoofed source address can't be used to reflect large answers.
+    pub fn with_required_above(mut self, len: u16) -> Self {
+        self.required_above = Some(len);
+        self
+    }
+
+    /// Largest UDP answer a client without a valid server cookie can get
+    pub fn required_above(&self) -> Option<u16> {
+        self.required_above
+    }
+
+    /// Starts signing with a new secret. Cookies signed with the one before are still accepted.
+    pub fn rotate(&self) {
+        self.secrets.lock().unwrap().rotate();
+    }
+
+    /// Current and previous secret, rotated first if they're due. Checking and rotating under the same lock means
+    /// queries racing past the deadline rotate once between them, rather than each pushing out `previous`.
+    fn secrets(&self) -> ([u8; 16], [u8; 16]) {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated_at.elapsed() >= self.rotate_every {
+            secrets.rotate();
+        }
+        (secrets.current, secrets.previous)
+    }
+
+    /// Cookie to send back to a client, with a fresh server cookie
+    pub fn respond(&self, cookie: &Cookie, client: IpAddr) -> Cookie {
+        let (secret, _) = self.secrets();
+        Cookie {
+            client: cookie.client,
+            server: Some(server_cookie(&secret, &cookie.client, client, unix_time()).to_vec()),
+        }
+    }
+
+    /// Whether the server cookie was made by us, for this client, and not too long ago
+    pub fn verify(&self, cookie: &Cookie, client: IpAddr) -> bool {
+        let (current, previous) = self.secrets();
+        verify_server_cookie(&[current, previous], cookie, client, unix_time())
+    }
+}
+
+impl Default for ServerCookies {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Version, 3 reserved bytes, timestamp, then the SipHash-2-4 of all that plus the client cookie and address
+fn server_cookie(secret: &[u8; 16], client_cookie: &[u8; CLIENT_COOKIE_LEN], client: IpAddr, timestamp: u32) -> [u8; SERVER_COOKIE_LEN] {
+    let mut cookie = [0; SERVER_COOKIE_LEN];
+    cookie[0] = SERVER_COOKIE_VERSION;
+    cookie[4..8].copy_from_slice(&timestamp.to_be_bytes());
+
+    let mut hasher = SipHasher24::new_with_key(secret);
+    hasher.write(client_cookie);
+    hasher.write(&cookie[..8]);
+    match client {
+        IpAddr::V4(ip) => hasher.write(&ip.octets()),
+        IpAddr::V6(ip) => hasher.write(&ip.octets()),
+    }
+    cookie[8..].copy_from_slice(&hasher.finish().to_le_bytes());
+    cookie
+}
+
+fn verify_server_cookie(secrets: &[[u8; 16]], cookie: &Cookie, client: IpAddr, now: u32) -> bool {
+    let Some(server) = cookie.server.as_deref() else {
+        return false;
+    };
+    if server.len() != SERVER_COOKIE_LEN || server[0] != SERVER_COOKIE_VERSION {
+        return false;
+    }
+
+    // Serial number arithmetic, so that timestamps keep working when the 32 bit clock wraps
+    let timestamp = u32::from_be_bytes(server[4..8].try_into().unwrap());
+    let age = now.wrapping_sub(timestamp);
+    if age > COOKIE_LIFETIME && age <= u32::MAX - CLOCK_SKEW {
+        return false;
+    }
+
+    secrets
+        .iter()
+        .any(|secret| server_cookie(secret, &cookie.client, client, timestamp) == server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: [u8; 16] = *b"\xe5\xe9\x73\xe5\xa6\xb2\xa4\x3f\x48\xe7\xdc\x84\x9e\x37\xbf\xcf";
+    const CLIENT_COOKIE: [u8; 8] = *b"\x24\x64\xc4\xab\xcf\x10\xc9\x57";
+
+    #[test]
+    fn test_rfc9018_vector() {
+        // RFC 9018 appendix A.1
+        let client = IpAddr::from([198, 51, 100, 100]);
+        let server = server_cookie(&SECRET, &CLIENT_COOKIE, client, 1559731985);
+        assert_eq!(server, *b"\x01\x00\x00\x00\x5c\xf7\x9f\x11\x1f\x81\x30\xc3\xee\xe2\x94\x80");
+
+        let cookie = Cookie {
+            client: CLIENT_COOKIE,
+            server: Some(server.to_vec()),
+        };
+        assert!(verify_server_cookie(&[SECRET], &cookie, client, 1559731985 + 1800));
+        assert!(verify_server_cookie(&[SECRET], &cookie, client, 1559731985 - 200));
+        assert!(!verify_server_cookie(&[SECRET], &cookie, client, 1559731985 + 3601));
+        assert!(!verify_server_cookie(&[SECRET], &cookie, client, 1559731985 - 301));
+        assert!(!verify_server_cookie(&[SECRET], &cookie, IpAddr::from([198, 51, 100, 101]), 1559731985));
+        assert!(!verify_server_cookie(&[[0; 16]], &cookie, client, 1559731985));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let cookies = ServerCookies::new();
+        let client = IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]);
+        let cookie = cookies.respond(
+            &Cookie {
+                client: CLIENT_COOKIE,
+                server: None,
+            },
+            client,
+        );
+        assert!(cookies.verify(&cookie, client));
+
+        cookies.rotate();
+        assert!(cookies.verify(&cookie, client));
+        cookies.rotate();
+        assert!(!cookies.verify(&cookie, client));
+    }
+
+    #[test]
+    fn test_concurrent_rotation() {
+        let mut cookies = ServerCookies::new();
+        cookies.rotate_every = Duration::from_millis(200);
+        let first = cookies.secrets.lock().unwrap().current;
+        std::thread::sleep(cookies.rotate_every);
+
+        // Every thread finds the secret due at once, but it's only rotated once
+        let barrier = std::sync::Barrier::new(8);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    barrier.wait();
+                    cookies.secrets();
+                });
+            }
+        });
+        assert_eq!(cookies.secrets.lock().unwrap().previous, first);
+    }
+
+    #[test]
+    fn test_parse_option() {
+        let cookie = Cookie::try_from(&EdnsOption::new(OPTION_COOKIE, CLIENT_COOKIE.to_vec())).unwrap();
+        assert_eq!(cookie.server, None);
+        assert_eq!(EdnsOption::from(&cookie).data, CLIENT_COOKIE);
+
+        for len in [0, 7, 9, 15, 41] {
+            assert!(Cookie::try_from(&EdnsOption::new(OPTION_COOKIE, vec![0; len])).is_err());
+        }
+    }
+}
@@ -1,72 +1,400 @@
 use crate::{
     dns::{
-        name::{DNSName, DNSNameCtxRtype},
-        rtypes::ContainsIP,
+        name::DNSName,
+        rtypes::RType,
+        svcb::Svcb,
+        text::{escape, DNSText},
+        tsig::Tsig,
+        wire::{Reader, WireError, Writer},
     },
-    utils::bv_to_vec,
+    utils::to_hex,
 };
-use deku::{
-    bitvec::{BitSlice, BitVec, Msb0},
-    ctx::Endian,
-    DekuError, DekuRead, DekuWrite,
+use std::{
+    fmt::{Display, Formatter},
+    net::{Ipv4Addr, Ipv6Addr},
 };
-use crate::dns::text::DNSText;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum RData {
-    Vec(Vec<u8>),
-    Name(DNSName),
-    Text(DNSText)
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    CNAME(DNSName),
+    NS(DNSName),
+    PTR(DNSName),
+    MX {
+        preference: u16,
+        exchange: DNSName,
+    },
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DNSName,
+    },
+    SOA {
+        mname: DNSName,
+        rname: DNSName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    TXT(DNSText),
+    /// RFC 8659. `tag` is issue, issuewild, iodef or any other property; bit 7 of `flags` is the critical flag.
+    CAA {
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+    },
+    SVCB(Svcb),
+    HTTPS(Svcb),
+    TSIG(Tsig),
+    /// RDATA of a type we don't decode, kept as-is
+    Opaque(Vec<u8>),
 }
 
 impl RData {
+    /// The record type this RDATA belongs to, unless it's opaque
+    pub fn rtype(&self) -> Option<RType> {
+        Some(match self {
+            RData::A(_) => RType::A,
+            RData::AAAA(_) => RType::AAAA,
+            RData::CNAME(_) => RType::CNAME,
+            RData::NS(_) => RType::NS,
+            RData::PTR(_) => RType::PTR,
+            RData::MX { .. } => RType::MX,
+            RData::SRV { .. } => RType::SRV,
+            RData::SOA { .. } => RType::SOA,
+            RData::TXT(_) => RType::TXT,
+            RData::CAA { .. } => RType::CAA,
+            RData::SVCB(_) => RType::SVCB,
+            RData::HTTPS(_) => RType::HTTPS,
+            RData::TSIG(_) => RType::TSIG,
+            RData::Opaque(_) => return None,
+        })
+    }
+
     pub fn try_get_name(&self) -> Option<&DNSName> {
         match self {
-            RData::Name(name) => Some(name),
-            _ => None
+            RData::CNAME(name) | RData::NS(name) | RData::PTR(name) => Some(name),
+            _ => None,
         }
     }
-}
 
-impl DekuWrite<DNSNameCtxRtype> for RData {
-    fn write(&self, output: &mut BitVec<u8, Msb0>, ctx: DNSNameCtxRtype) -> Result<(), DekuError> {
-        let mut output_data = BitVec::new();
+    /// Name that needs its addresses in the additional section when this record is in a response (RFC 1035 3.3.9,
+    /// RFC 2782, RFC 9460 section 4.1)
+    pub fn additional_name(&self) -> Option<&DNSName> {
+        let name = match self {
+            RData::NS(name) => name,
+            RData::MX { exchange, .. } => exchange,
+            RData::SRV { target, .. } => target,
+            RData::SVCB(svcb) | RData::HTTPS(svcb) => &svcb.target,
+            _ => return None,
+        };
+        // A target of "." means the service isn't available
+        (!name.is_empty()).then_some(name)
+    }
+
+    /// Decodes RDATA given on its own rather than inside a message, e.g. from the RFC 3597 generic text format.
+    /// Names in it can't be compressed.
+    pub(crate) fn from_bytes(bytes: &[u8], rtype: RType) -> Result<Self, WireError> {
+        let mut reader = Reader::detached(bytes);
+        let rdata = RData::read_data(&mut reader, rtype)?;
+        if !reader.is_empty() {
+            return Err(WireError::Parse(format!(
+                "{} bytes left over after {:?} RDATA",
+                reader.remaining(),
+                rtype
+            )));
+        }
+        Ok(rdata)
+    }
+
+    /// RDATA as it goes on the wire, with names in full
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>, WireError> {
+        let mut output = Vec::new();
+        self.write_data(&mut Writer::new(&mut output), false)?;
+        Ok(output)
+    }
+
+    /// Writes RDLENGTH and the RDATA. Names inside it are compressed if `compress`.
+    pub(crate) fn write(&self, writer: &mut Writer<'_>, compress: bool) -> Result<(), WireError> {
+        writer.length_prefixed("RDATA", |writer| self.write_data(writer, compress))
+    }
+
+    fn write_data(&self, writer: &mut Writer<'_>, compress: bool) -> Result<(), WireError> {
         match self {
-            RData::Vec(vec) => {
-                vec.write(&mut output_data, ctx.endian)?;
+            RData::A(ip) => writer.bytes(&ip.octets()),
+            RData::AAAA(ip) => writer.bytes(&ip.octets()),
+            RData::CNAME(name) | RData::NS(name) | RData::PTR(name) => name.write(writer, compress)?,
+            RData::MX { preference, exchange } => {
+                writer.u16(*preference);
+                exchange.write(writer, compress)?;
             }
-            RData::Name(name) => {
-                name.write(&mut output_data, ctx.clone())?;
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                writer.u16(*priority);
+                writer.u16(*weight);
+                writer.u16(*port);
+                target.write(writer, compress)?;
             }
-            RData::Text(text) => {
-                text.write(&mut output_data, ctx.clone())?;
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                mname.write(writer, compress)?;
+                rname.write(writer, compress)?;
+                for field in [serial, refresh, retry, expire, minimum] {
+                    writer.u32(*field);
+                }
             }
+            RData::TXT(text) => text.write(writer),
+            RData::CAA { flags, tag, value } => {
+                let tag_len = u8::try_from(tag.len()).map_err(|_| WireError::InvalidParam("CAA tag too long".into()))?;
+                writer.u8(*flags);
+                writer.u8(tag_len);
+                writer.bytes(tag.as_bytes());
+                writer.bytes(value);
+            }
+            RData::SVCB(svcb) | RData::HTTPS(svcb) => svcb.write(writer)?,
+            RData::TSIG(tsig) => tsig.write(writer),
+            RData::Opaque(data) => writer.bytes(data),
         }
-
-        let bytes_written = u16::try_from(output_data.len() / 8).unwrap();
-        bytes_written.write(output, ctx.endian)?;
-
-        output.append(&mut output_data);
-
         Ok(())
     }
-}
 
-impl DekuRead<'_, ContainsIP> for RData {
-    fn read(input: &BitSlice<u8, Msb0>, ctx: ContainsIP) -> Result<(&BitSlice<u8, Msb0>, Self), DekuError> {
-        let (input, bytes_written) = u16::read(input, Endian::Big)?;
-        let bytes_written = bytes_written as usize;
+    /// Reads RDLENGTH and the RDATA of type `rtype` after it. Names inside the RDATA may point anywhere earlier in
+    /// the message.
+    pub(crate) fn read(reader: &mut Reader<'_>, rtype: RType) -> Result<Self, WireError> {
+        let len = reader.u16()?;
+        reader.limited(len as usize, |rdata| {
+            let parsed = RData::read_data(rdata, rtype)?;
+            if !rdata.is_empty() {
+                return Err(WireError::Parse(format!("{} bytes left over after {:?} RDATA", rdata.remaining(), rtype)));
+            }
+            Ok(parsed)
+        })
+    }
 
-        match ctx {
-            ContainsIP::No => {
-                let (input, vec) = input.split_at(bytes_written * 8);
-                let vec_u8 = bv_to_vec(vec.to_bitvec());
-                Ok((input, RData::Vec(vec_u8)))
+    /// Decodes RDATA of type `rtype`. `reader` must be limited to the RDLENGTH bytes.
+    fn read_data(reader: &mut Reader<'_>, rtype: RType) -> Result<Self, WireError> {
+        Ok(match rtype {
+            RType::A => RData::A(Ipv4Addr::from(reader.array::<4>()?)),
+            RType::AAAA => RData::AAAA(Ipv6Addr::from(reader.array::<16>()?)),
+            RType::CNAME => RData::CNAME(DNSName::read(reader)?),
+            RType::NS => RData::NS(DNSName::read(reader)?),
+            RType::PTR => RData::PTR(DNSName::read(reader)?),
+            RType::MX => RData::MX {
+                preference: reader.u16()?,
+                exchange: DNSName::read(reader)?,
+            },
+            RType::SRV => RData::SRV {
+                priority: reader.u16()?,
+                weight: reader.u16()?,
+                port: reader.u16()?,
+                target: DNSName::read(reader)?,
+            },
+            RType::SOA => RData::SOA {
+                mname: DNSName::read(reader)?,
+                rname: DNSName::read(reader)?,
+                serial: reader.u32()?,
+                refresh: reader.u32()?,
+                retry: reader.u32()?,
+                expire: reader.u32()?,
+                minimum: reader.u32()?,
+            },
+            RType::TXT => RData::TXT(DNSText::read(reader)?),
+            RType::CAA => {
+                let flags = reader.u8()?;
+                let tag_len = reader.u8()?;
+                let tag = reader.bytes(tag_len as usize)?;
+                if !is_caa_tag(tag) {
+                    return Err(WireError::Parse("CAA tag isn't 1 to 15 letters and digits".into()));
+                }
+                let tag = String::from_utf8_lossy(tag).into_owned();
+                let value = reader.rest().to_vec();
+                RData::CAA { flags, tag, value }
             }
-            ContainsIP::Yes => {
-                let (input, name) = DNSName::read(input, ())?;
-                Ok((input, RData::Name(name)))
+            RType::SVCB => RData::SVCB(Svcb::read(reader)?),
+            RType::HTTPS => RData::HTTPS(Svcb::read(reader)?),
+            RType::TSIG => RData::TSIG(Tsig::read(reader)?),
+            _ => RData::Opaque(reader.rest().to_vec()),
+        })
+    }
+}
+
+impl Display for RData {
+    /// Master file format, which `TryFrom<&str> for DNSRecord` reads back. RDATA we don't decode and TSIG, which
+    /// has no text format, are written in the generic `\# length hex` format (RFC 3597 section 5).
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RData::A(ip) => write!(f, "{}", ip),
+            RData::AAAA(ip) => write!(f, "{}", ip),
+            RData::CNAME(name) | RData::NS(name) | RData::PTR(name) => f.write_str(&name.to_fqdn()),
+            RData::MX { preference, exchange } => write!(f, "{} {}", preference, exchange.to_fqdn()),
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{} {} {} {}", priority, weight, port, target.to_fqdn()),
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                mname.to_fqdn(),
+                rname.to_fqdn(),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            RData::TXT(text) => write!(f, "{}", text),
+            RData::CAA { flags, tag, value } => {
+                let mut output = String::new();
+                value.iter().for_each(|&b| escape(b, b"\"", &mut output));
+                write!(f, "{} {} \"{}\"", flags, tag, output)
             }
+            RData::SVCB(svcb) | RData::HTTPS(svcb) => write!(f, "{}", svcb),
+            RData::TSIG(tsig) => write_generic(f, &tsig.to_bytes()),
+            RData::Opaque(data) => write_generic(f, data),
         }
     }
 }
+
+/// RFC 8659 section 4.1: CAA tags are 1 to 15 ASCII letters and digits
+pub(crate) fn is_caa_tag(tag: &[u8]) -> bool {
+    (1..=15).contains(&tag.len()) && tag.iter().all(u8::is_ascii_alphanumeric)
+}
+
+fn write_generic(f: &mut Formatter<'_>, data: &[u8]) -> std::fmt::Result {
+    match data {
+        [] => f.write_str("\\# 0"),
+        data => write!(f, "\\# {} {}", data.len(), to_hex(data)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(rtype: RType, rdata: RData) {
+        let mut output = Vec::new();
+        rdata.write(&mut Writer::new(&mut output), false).unwrap();
+
+        let mut reader = Reader::new(&output);
+        let parsed = RData::read(&mut reader, rtype).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(parsed, rdata);
+    }
+
+    #[test]
+    fn test_roundtrip_addresses() {
+        roundtrip(RType::A, RData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        roundtrip(RType::AAAA, RData::AAAA("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_roundtrip_names() {
+        let name = DNSName::from_url("www.example.com");
+        roundtrip(RType::CNAME, RData::CNAME(name.clone()));
+        roundtrip(RType::NS, RData::NS(name.clone()));
+        roundtrip(RType::PTR, RData::PTR(name.clone()));
+        roundtrip(
+            RType::MX,
+            RData::MX {
+                preference: 10,
+                exchange: name.clone(),
+            },
+        );
+        roundtrip(
+            RType::SRV,
+            RData::SRV {
+                priority: 1,
+                weight: 2,
+                port: 443,
+                target: name,
+            },
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_soa() {
+        roundtrip(
+            RType::SOA,
+            RData::SOA {
+                mname: DNSName::from_url("ns1.example.com"),
+                rname: DNSName::from_url("hostmaster.example.com"),
+                serial: 2023010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            },
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_other() {
+        roundtrip(RType::TXT, RData::TXT(DNSText::from("hello world".to_string())));
+        roundtrip(RType::Unknown(999), RData::Opaque(vec![1, 2, 3]));
+        roundtrip(
+            RType::CAA,
+            RData::CAA {
+                flags: 128,
+                tag: "issue".to_string(),
+                value: b"letsencrypt.org".to_vec(),
+            },
+        );
+        roundtrip(RType::HTTPS, RData::HTTPS("1 . alpn=h3,h2 port=443".parse().unwrap()));
+        roundtrip(RType::SVCB, RData::SVCB("0 svc.example.com".parse().unwrap()));
+        roundtrip(
+            RType::TSIG,
+            RData::TSIG(Tsig {
+                algorithm: DNSName::from_url("hmac-sha256"),
+                time_signed: 1_700_000_000,
+                fudge: 300,
+                mac: vec![7; 32],
+                original_id: 0x1234,
+                error: 0,
+                other: Vec::new(),
+            }),
+        );
+    }
+
+    #[test]
+    fn test_caa_tag() {
+        assert!(RData::from_bytes(b"\x00\x05issueca.example.net", RType::CAA).is_ok());
+        assert!(RData::from_bytes(b"\x00\x00ca.example.net", RType::CAA).is_err());
+        assert!(RData::from_bytes(b"\x00\x06is-sueca.example.net", RType::CAA).is_err());
+        assert!(RData::from_bytes(&[&[0, 16], &[b'a'; 16][..]].concat(), RType::CAA).is_err());
+    }
+
+    #[test]
+    fn test_rdlength_mismatch() {
+        // A record with 5 bytes of RDATA
+        let input = [0, 5, 10, 0, 0, 1, 0];
+        assert!(RData::read(&mut Reader::new(&input), RType::A).is_err());
+        // RDLENGTH past the end of the message
+        assert_eq!(RData::read(&mut Reader::new(&input[..6]), RType::A), Err(WireError::Incomplete));
+    }
+}
@@ -0,0 +1,174 @@
+// EDNS Client Subnet (RFC 7871)
+
+use crate::dns::edns::EdnsOption;
+use anyhow::anyhow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// EDNS option code of a client subnet
+pub const OPTION_ECS: u16 = 8;
+
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+/// Network of the end user a resolver is asking on behalf of.
+/// `source_prefix` is how much of the address the resolver revealed. `scope_prefix` is how much of it the answer
+/// depends on: always 0 in queries, and set by whoever builds the answer in responses.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ClientSubnet {
+    addr: IpAddr,
+    source_prefix: u8,
+    scope_prefix: u8,
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Zeroes everything past the first `prefix` bits
+fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0))),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0))),
+    }
+}
+
+impl ClientSubnet {
+    /// Subnet of the first `source_prefix` bits of `addr`. The rest of the address is dropped.
+    pub fn new(addr: IpAddr, source_prefix: u8) -> anyhow::Result<Self> {
+        if source_prefix > max_prefix(addr) {
+            return Err(anyhow!("Prefix /{} is too long for {}", source_prefix, addr));
+        }
+        Ok(ClientSubnet {
+            addr: mask(addr, source_prefix),
+            source_prefix,
+            scope_prefix: 0,
+        })
+    }
+
+    /// Network address, with every bit past the source prefix cleared
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn source_prefix(&self) -> u8 {
+        self.source_prefix
+    }
+
+    pub fn scope_prefix(&self) -> u8 {
+        self.scope_prefix
+    }
+
+    /// The same subnet, answered for all clients sharing the first `scope_prefix` bits. 0 means the answer is the
+    /// same wherever the client is.
+    pub fn with_scope(&self, scope_prefix: u8) -> Self {
+        ClientSubnet {
+            scope_prefix: scope_prefix.min(max_prefix(self.addr)),
+            ..*self
+        }
+    }
+
+    /// Whether `ip` is in this subnet
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.addr.is_ipv4() && mask(ip, self.source_prefix) == self.addr
+    }
+}
+
+impl TryFrom<&EdnsOption> for ClientSubnet {
+    type Error = anyhow::Error;
+
+    /// Only as many address bytes as the source prefix needs are sent, and the bits past it must be zero
+    /// (RFC 7871 6). Anything else is a format error.
+    fn try_from(option: &EdnsOption) -> Result<Self, Self::Error> {
+        if option.code != OPTION_ECS {
+            return Err(anyhow!("Option {} isn't a client subnet", option.code));
+        }
+        let [f1, f2, source_prefix, scope_prefix, address @ ..] = option.data.as_slice() else {
+            return Err(anyhow!("Client subnet option is too short"));
+        };
+
+        let addr = match u16::from_be_bytes([*f1, *f2]) {
+            FAMILY_IPV4 => IpAddr::V4(Ipv4Addr::from(padded::<4>(address)?)),
+            FAMILY_IPV6 => IpAddr::V6(Ipv6Addr::from(padded::<16>(address)?)),
+            family => return Err(anyhow!("Unknown client subnet address family {}", family)),
+        };
+        if address.len() != (*source_prefix as usize).div_ceil(8) {
+            return Err(anyhow!(
+                "Client subnet address has {} bytes for a /{} prefix",
+                address.len(),
+                source_prefix
+            ));
+        }
+        let subnet = ClientSubnet::new(addr, *source_prefix)?;
+        if subnet.addr != addr {
+            return Err(anyhow!("Client subnet address has bits set past its /{} prefix", source_prefix));
+        }
+        if *scope_prefix > max_prefix(addr) {
+            return Err(anyhow!("Scope prefix /{} is too long for {}", scope_prefix, addr));
+        }
+        Ok(subnet.with_scope(*scope_prefix))
+    }
+}
+
+fn padded<const N: usize>(bytes: &[u8]) -> anyhow::Result<[u8; N]> {
+    let mut addr = [0; N];
+    addr.get_mut(..bytes.len())
+        .ok_or_else(|| anyhow!("Client subnet address is too long"))?
+        .copy_from_slice(bytes);
+    Ok(addr)
+}
+
+impl From<&ClientSubnet> for EdnsOption {
+    fn from(subnet: &ClientSubnet) -> Self {
+        let (family, octets) = match subnet.addr {
+            IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+        };
+        let mut data = family.to_be_bytes().to_vec();
+        data.extend([subnet.source_prefix, subnet.scope_prefix]);
+        data.extend(&octets[..(subnet.source_prefix as usize).div_ceil(8)]);
+        EdnsOption::new(OPTION_ECS, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let option = EdnsOption::new(OPTION_ECS, vec![0, 1, 20, 0, 198, 51, 96]);
+        let subnet = ClientSubnet::try_from(&option).unwrap();
+        assert_eq!(subnet.addr(), IpAddr::from([198, 51, 96, 0]));
+        assert_eq!((subnet.source_prefix(), subnet.scope_prefix()), (20, 0));
+        assert!(subnet.contains(IpAddr::from([198, 51, 111, 1])));
+        assert!(!subnet.contains(IpAddr::from([198, 51, 112, 1])));
+        assert_eq!(EdnsOption::from(&subnet), option);
+
+        let subnet = ClientSubnet::try_from(&EdnsOption::new(OPTION_ECS, vec![0, 2, 0, 0])).unwrap();
+        assert!(subnet.contains(IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1])));
+        assert!(!subnet.contains(IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(EdnsOption::from(&subnet.with_scope(200)).data, [0, 2, 0, 128]);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for data in [
+            vec![0, 1, 24],
+            // Family 3
+            vec![0, 3, 0, 0],
+            // Too many or too few address bytes for the prefix
+            vec![0, 1, 24, 0, 198, 51, 100, 1],
+            vec![0, 1, 24, 0, 198, 51],
+            // Host bits set
+            vec![0, 1, 20, 0, 198, 51, 100],
+            // Prefixes longer than the address
+            vec![0, 1, 33, 0, 198, 51, 100, 1, 0],
+            vec![0, 1, 24, 33, 198, 51, 100],
+        ] {
+            assert!(ClientSubnet::try_from(&EdnsOption::new(OPTION_ECS, data.clone())).is_err(), "{:?}", data);
+        }
+    }
+}
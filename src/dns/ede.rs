@@ -0,0 +1,164 @@
+// Extended DNS Errors (RFC 8914)
+
+use crate::dns::edns::EdnsOption;
+use anyhow::anyhow;
+
+/// EDNS option code of an extended error
+pub const OPTION_EDE: u16 = 15;
+
+/// INFO-CODE of an extended error, telling the client why a query failed beyond what the RCODE says
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EdeCode {
+    /// Nothing else fits; the extra text explains
+    Other,
+    UnsupportedDnskeyAlgorithm,
+    UnsupportedDsDigestType,
+    StaleAnswer,
+    ForgedAnswer,
+    DnssecIndeterminate,
+    DnssecBogus,
+    SignatureExpired,
+    SignatureNotYetValid,
+    DnskeyMissing,
+    RrsigsMissing,
+    NoZoneKeyBitSet,
+    NsecMissing,
+    CachedError,
+    NotReady,
+    Blocked,
+    Censored,
+    Filtered,
+    Prohibited,
+    StaleNxdomainAnswer,
+    NotAuthoritative,
+    NotSupported,
+    NoReachableAuthority,
+    NetworkError,
+    InvalidData,
+    Unknown(u16),
+}
+
+impl From<u16> for EdeCode {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => EdeCode::Other,
+            1 => EdeCode::UnsupportedDnskeyAlgorithm,
+            2 => EdeCode::UnsupportedDsDigestType,
+            3 => EdeCode::StaleAnswer,
+            4 => EdeCode::ForgedAnswer,
+            5 => EdeCode::DnssecIndeterminate,
+            6 => EdeCode::DnssecBogus,
+            7 => EdeCode::SignatureExpired,
+            8 => EdeCode::SignatureNotYetValid,
+            9 => EdeCode::DnskeyMissing,
+            10 => EdeCode::RrsigsMissing,
+            11 => EdeCode::NoZoneKeyBitSet,
+            12 => EdeCode::NsecMissing,
+            13 => EdeCode::CachedError,
+            14 => EdeCode::NotReady,
+            15 => EdeCode::Blocked,
+            16 => EdeCode::Censored,
+            17 => EdeCode::Filtered,
+            18 => EdeCode::Prohibited,
+            19 => EdeCode::StaleNxdomainAnswer,
+            20 => EdeCode::NotAuthoritative,
+            21 => EdeCode::NotSupported,
+            22 => EdeCode::NoReachableAuthority,
+            23 => EdeCode::NetworkError,
+            24 => EdeCode::InvalidData,
+            x => EdeCode::Unknown(x),
+        }
+    }
+}
+
+impl From<EdeCode> for u16 {
+    fn from(value: EdeCode) -> Self {
+        match value {
+            EdeCode::Other => 0,
+            EdeCode::UnsupportedDnskeyAlgorithm => 1,
+            EdeCode::UnsupportedDsDigestType => 2,
+            EdeCode::StaleAnswer => 3,
+            EdeCode::ForgedAnswer => 4,
+            EdeCode::DnssecIndeterminate => 5,
+            EdeCode::DnssecBogus => 6,
+            EdeCode::SignatureExpired => 7,
+            EdeCode::SignatureNotYetValid => 8,
+            EdeCode::DnskeyMissing => 9,
+            EdeCode::RrsigsMissing => 10,
+            EdeCode::NoZoneKeyBitSet => 11,
+            EdeCode::NsecMissing => 12,
+            EdeCode::CachedError => 13,
+            EdeCode::NotReady => 14,
+            EdeCode::Blocked => 15,
+            EdeCode::Censored => 16,
+            EdeCode::Filtered => 17,
+            EdeCode::Prohibited => 18,
+            EdeCode::StaleNxdomainAnswer => 19,
+            EdeCode::NotAuthoritative => 20,
+            EdeCode::NotSupported => 21,
+            EdeCode::NoReachableAuthority => 22,
+            EdeCode::NetworkError => 23,
+            EdeCode::InvalidData => 24,
+            EdeCode::Unknown(x) => x,
+        }
+    }
+}
+
+/// Extended error option. The extra text is meant for humans (`dig` prints it) and may be empty.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ExtendedError {
+    pub code: EdeCode,
+    pub extra_text: String,
+}
+
+impl ExtendedError {
+    pub fn new(code: EdeCode, extra_text: impl Into<String>) -> Self {
+        ExtendedError {
+            code,
+            extra_text: extra_text.into(),
+        }
+    }
+}
+
+impl From<&ExtendedError> for EdnsOption {
+    fn from(error: &ExtendedError) -> Self {
+        let mut data = u16::from(error.code).to_be_bytes().to_vec();
+        data.extend_from_slice(error.extra_text.as_bytes());
+        EdnsOption::new(OPTION_EDE, data)
+    }
+}
+
+impl TryFrom<&EdnsOption> for ExtendedError {
+    type Error = anyhow::Error;
+
+    fn try_from(option: &EdnsOption) -> Result<Self, Self::Error> {
+        if option.code != OPTION_EDE {
+            return Err(anyhow!("Option {} isn't an extended error", option.code));
+        }
+        let [high, low, text @ ..] = option.data.as_slice() else {
+            return Err(anyhow!("Extended error option is missing its INFO-CODE"));
+        };
+        // The text should be UTF-8 but isn't guaranteed to be, and it's only informational
+        Ok(ExtendedError::new(
+            EdeCode::from(u16::from_be_bytes([*high, *low])),
+            String::from_utf8_lossy(text),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_option_roundtrip() {
+        let error = ExtendedError::new(EdeCode::Prohibited, "Not allowed");
+        let option = EdnsOption::from(&error);
+        assert_eq!(option.data, b"\x00\x12Not allowed");
+        assert_eq!(ExtendedError::try_from(&option).unwrap(), error);
+
+        let option = EdnsOption::new(OPTION_EDE, vec![0x01, 0x00]);
+        assert_eq!(ExtendedError::try_from(&option).unwrap(), ExtendedError::new(EdeCode::Unknown(256), ""));
+        assert!(ExtendedError::try_from(&EdnsOption::new(OPTION_EDE, vec![0])).is_err());
+    }
+}
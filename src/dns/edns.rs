@@ -0,0 +1,268 @@
+// EDNS(0) OPT pseudo-record (RFC 6891)
+
+use crate::{
+    dns::{
+        data::RData,
+        ecs::{ClientSubnet, OPTION_ECS},
+        ede::{ExtendedError, OPTION_EDE},
+        header::Rcode,
+        padding::OPTION_PADDING,
+        record::{DNSRecord, VecDNSRecord},
+        rtypes::RType,
+        wire::{Reader, WireError, Writer},
+    },
+    utils::to_hex,
+};
+use std::fmt::{Display, Formatter};
+
+/// Largest UDP payload we advertise and are willing to send, following the DNS flag day 2020 recommendation.
+pub const SERVER_UDP_PAYLOAD: u16 = 1232;
+
+/// The only EDNS version that exists
+pub const EDNS_VERSION: u8 = 0;
+
+/// DNS Cookie option code (RFC 7873)
+pub const OPTION_COOKIE: u16 = 10;
+
+/// RCODEs that need more than the 4 header bits. The upper 8 bits are carried in the OPT record.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u16)]
+pub enum ExtendedRcode {
+    BadVers = 16,
+    /// The server cookie was missing or invalid and the server requires one (RFC 7873 5.2.3)
+    BadCookie = 23,
+}
+
+impl ExtendedRcode {
+    /// Splits the code into the header RCODE and the OPT extended RCODE
+    pub(crate) fn split(self) -> (Rcode, u8) {
+        let code = self as u16;
+        (Rcode::from_low_bits(code as u8 & 0xF), (code >> 4) as u8)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl EdnsOption {
+    pub fn new(code: u16, data: Vec<u8>) -> Self {
+        EdnsOption { code, data }
+    }
+}
+
+impl Display for EdnsOption {
+    /// Like dig's OPT pseudosection. Options we know are decoded, anything else (or malformed) is shown as hex.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.code {
+            OPTION_COOKIE => write!(f, "COOKIE: {}", to_hex(&self.data)),
+            OPTION_ECS => match ClientSubnet::try_from(self) {
+                Ok(subnet) => write!(f, "CLIENT-SUBNET: {}/{}/{}", subnet.addr(), subnet.source_prefix(), subnet.scope_prefix()),
+                Err(_) => write!(f, "CLIENT-SUBNET: {}", to_hex(&self.data)),
+            },
+            OPTION_EDE => match ExtendedError::try_from(self) {
+                Ok(error) if error.extra_text.is_empty() => write!(f, "EDE: {} ({:?})", u16::from(error.code), error.code),
+                Ok(error) => write!(f, "EDE: {} ({:?}): ({})", u16::from(error.code), error.code, error.extra_text),
+                Err(_) => write!(f, "EDE: {}", to_hex(&self.data)),
+            },
+            OPTION_PADDING => write!(f, "PADDING: ({} bytes)", self.data.len()),
+            code => write!(f, "OPT={}: {}", code, to_hex(&self.data)),
+        }
+    }
+}
+
+impl EdnsOption {
+    pub(crate) fn write(&self, writer: &mut Writer<'_>) -> Result<(), WireError> {
+        writer.u16(self.code);
+        writer.length_prefixed("EDNS option", |writer| {
+            writer.bytes(&self.data);
+            Ok(())
+        })
+    }
+
+    pub(crate) fn read(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        let code = reader.u16()?;
+        let len = reader.u16()?;
+        let data = reader.bytes(len as usize)?.to_vec();
+        Ok(EdnsOption { code, data })
+    }
+}
+
+/// OPT record, as found in the additional section.
+/// The owner name is always the root, the CLASS field holds the UDP payload size and the TTL holds the flags.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    /// First option with the given code
+    pub fn option(&self, code: u16) -> Option<&EdnsOption> {
+        self.options.iter().find(|option| option.code == code)
+    }
+
+    pub fn new(udp_payload_size: u16) -> Self {
+        Edns {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: EDNS_VERSION,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// Pulls the OPT record out of a parsed additional section. More than one OPT record is a format error.
+    pub(crate) fn from_additional(additional: &VecDNSRecord) -> Result<Option<Self>, WireError> {
+        let mut opts = additional.iter().filter(|r| r.rtype == RType::OPT);
+        let edns = opts.next().map(Edns::try_from).transpose()?;
+        if opts.next().is_some() {
+            return Err(WireError::Parse("Multiple OPT records".into()));
+        }
+        Ok(edns)
+    }
+
+    fn ttl(&self) -> u32 {
+        (self.extended_rcode as u32) << 24 | (self.version as u32) << 16 | (self.dnssec_ok as u32) << 15
+    }
+}
+
+impl Display for Edns {
+    /// dig's OPT pseudosection. The extended RCODE is left out: it's part of the status in the header.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let flags = if self.dnssec_ok { " do" } else { "" };
+        write!(f, "; EDNS: version: {}, flags:{}; udp: {}", self.version, flags, self.udp_payload_size)?;
+        for option in &self.options {
+            write!(f, "\n; {}", option)?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&DNSRecord> for Edns {
+    type Error = WireError;
+
+    fn try_from(record: &DNSRecord) -> Result<Self, Self::Error> {
+        if !record.name.is_empty() {
+            return Err(WireError::Parse("OPT record owner must be the root".into()));
+        }
+        let RData::Opaque(rdata) = &record.rdata else {
+            return Err(WireError::Parse("Invalid OPT RDATA".into()));
+        };
+
+        let mut options = Vec::new();
+        let mut reader = Reader::detached(rdata);
+        while !reader.is_empty() {
+            options.push(EdnsOption::read(&mut reader)?);
+        }
+
+        Ok(Edns {
+            udp_payload_size: record.class,
+            extended_rcode: (record.ttl >> 24) as u8,
+            version: (record.ttl >> 16) as u8,
+            dnssec_ok: record.ttl & 0x8000 != 0,
+            options,
+        })
+    }
+}
+
+impl Edns {
+    pub(crate) fn write(&self, writer: &mut Writer<'_>) -> Result<(), WireError> {
+        // Root owner name
+        writer.u8(0);
+        writer.u16(RType::OPT.into());
+        writer.u16(self.udp_payload_size);
+        writer.u32(self.ttl());
+        writer.length_prefixed("OPT RDATA", |writer| self.options.iter().try_for_each(|option| option.write(writer)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{
+        name::DNSName,
+        question::{DNSQuestion, Question},
+        response::Response,
+    };
+
+    // dig example.com A +bufsize=4096 +dnssec, with one cookie option
+    const QUERY: &[u8] = &[
+        0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // header
+        7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x01, 0x00, 0x01, // question
+        0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x0C, // OPT header
+        0x00, 0x0A, 0x00, 0x08, 1, 2, 3, 4, 5, 6, 7, 8, // COOKIE option
+    ];
+
+    #[test]
+    fn test_parse_opt() {
+        let question = Question::parse(QUERY).unwrap();
+        let edns = question.edns.unwrap();
+
+        assert_eq!(edns.udp_payload_size, 4096);
+        assert_eq!(edns.version, 0);
+        assert!(edns.dnssec_ok);
+        assert_eq!(edns.options, vec![EdnsOption::new(10, vec![1, 2, 3, 4, 5, 6, 7, 8])]);
+    }
+
+    #[test]
+    fn test_write_opt() {
+        let question = Question::parse(QUERY).unwrap();
+        let mut edns = question.edns.unwrap();
+        edns.udp_payload_size = 4096;
+        edns.options.clear();
+
+        let mut output = Vec::new();
+        edns.write(&mut Writer::new(&mut output)).unwrap();
+        assert_eq!(output, [0, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_badvers() {
+        let question = DNSQuestion::new(DNSName::from_url("example.com"), RType::A);
+        let mut response = Response::from_rcode(1, question, Rcode::NoError, false);
+        let mut client = Edns::new(4096);
+        client.version = 1;
+        response.set_edns(&client);
+        response.set_extended_return_code(ExtendedRcode::BadVers);
+
+        let bytes = response.serialize().unwrap();
+        // Header RCODE holds the low 4 bits, OPT holds the upper 8
+        assert_eq!(bytes[3] & 0xF, 0);
+        assert_eq!(bytes[11], 1);
+        assert_eq!(bytes[bytes.len() - 11..], [0, 0x00, 0x29, 0x04, 0xD0, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_truncation_uses_payload_size() {
+        let name = DNSName::from_url("example.com");
+        let records: Vec<_> = (0..40)
+            .map(|i| DNSRecord::try_from(format!("example.com A 10.0.0.{i}").as_str()).unwrap())
+            .collect();
+
+        // 40 A records are about 660 bytes: too much for plain DNS, but fine with a 1232 byte payload
+        let mut response = Response::new(
+            1,
+            DNSQuestion::new(name.clone(), RType::A),
+            records.clone(),
+            vec![],
+            vec![],
+            false,
+            Rcode::NoError,
+        );
+        response.serialize().unwrap();
+        assert!(response.header().truncated());
+
+        let mut response = Response::new(1, DNSQuestion::new(name, RType::A), records, vec![], vec![], false, Rcode::NoError);
+        response.set_edns(&Edns::new(4096));
+        let bytes = response.serialize().unwrap();
+        assert!(!response.header().truncated());
+        assert_eq!(bytes[6..8], [0, 40]);
+        assert_eq!(bytes[10..12], [0, 1]);
+    }
+}
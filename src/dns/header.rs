@@ -1,9 +1,7 @@
-use deku::{ctx::Endian, prelude::*};
+use crate::dns::wire::{Reader, WireError, Writer};
+use std::fmt::{Display, Formatter};
 
-use deku::bitvec::{BitSlice, BitVec, Msb0};
-
-#[derive(PartialEq, DekuRead, DekuWrite, Clone, Debug, Eq)]
-#[deku(bits = "4", type = "u8")]
+#[derive(PartialEq, Clone, Copy, Debug, Eq)]
 pub enum Rcode {
     /*
         DNS Return Code	DNS Return Message	Description
@@ -15,137 +13,395 @@ pub enum Rcode {
     RCODE:5	REFUSED	The server refused to answer for the query
     RCODE:6	YXDOMAIN	Name that should not exist, does exist
     RCODE:7	XRRSET	RRset that should not exist, does exist
-    RCODE:8	NOTAUTH	Server not authoritative for the zone
-    RCODE:9	NOTZONE
+    RCODE:8	NXRRSET	RRset that should exist, does not exist
+    RCODE:9	NOTAUTH	Server not authoritative for the zone, or the query failed its TSIG check
+    RCODE:10	NOTZONE
     Name not in zone
          */
-    NoError = 0,
-    FormatError = 1,
-    ServerFailure = 2,
-    NxDomain = 3,
-    NotImplemented = 4,
-    Refused = 5,
-    YXDomain = 6,
-    YXRRSet = 7,
-    NotAuth = 8,
-    NotZone = 9,
+    NoError,
+    FormatError,
+    ServerFailure,
+    NxDomain,
+    NotImplemented,
+    Refused,
+    YXDomain,
+    YXRRSet,
+    NXRRSet,
+    NotAuth,
+    NotZone,
+    /// One of the unassigned RCODEs 11-15, kept as it was received
+    Other(u8),
 }
 
-pub fn response_header(id: u16, answers: usize, authorities: usize, additionals: usize, is_tcp: bool, truncated: bool, rcode: Rcode) -> DNSHeader {
-    DNSHeader {
-        tcp_header_field: if is_tcp { TcpHeaderField(Some(0)) } else { TcpHeaderField(None) },
-        id,                          // ID
-        qr: 1,                       // Response (qr = 1)
-        opcode: 0,                   // Standard query (opcode = 0)
-        aa: 1,                       // Not authoritative (aa = 0)
-        tc: u8::from(truncated),     // Not truncated (tc = 0)
-        rd: 0,                       // Recursion desired (rd = 0)
-        ra: 0,                       // Recursion available (ra = 0)
-        z: 0,                        // Reserved bits (z = 0)
-        rcode,                       // No error condition (rcode = 0)
-        qdcount: 1,                  // Number of questions (qdcount = 0)
-        ancount: answers as u16,     // Number of answers (ancount = 1)
-        nscount: authorities as u16, // Number of authority resource records (nscount = 0)
-        arcount: additionals as u16, // Number of additional resource records (arcount = 0)
+impl Rcode {
+    /// Header RCODE for the low 4 bits of an extended RCODE
+    pub(crate) fn from_low_bits(bits: u8) -> Rcode {
+        match bits & 0xF {
+            0 => Rcode::NoError,
+            1 => Rcode::FormatError,
+            2 => Rcode::ServerFailure,
+            3 => Rcode::NxDomain,
+            4 => Rcode::NotImplemented,
+            5 => Rcode::Refused,
+            6 => Rcode::YXDomain,
+            7 => Rcode::YXRRSet,
+            8 => Rcode::NXRRSet,
+            9 => Rcode::NotAuth,
+            10 => Rcode::NotZone,
+            bits => Rcode::Other(bits),
+        }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-struct TcpHeaderField(Option<u16>);
-
-impl TcpHeaderField {
-    fn set(&mut self, value: u16) {
-        if let Some(a) = self.0.as_mut() {
-            *a = value;
+impl From<Rcode> for u8 {
+    fn from(value: Rcode) -> Self {
+        match value {
+            Rcode::NoError => 0,
+            Rcode::FormatError => 1,
+            Rcode::ServerFailure => 2,
+            Rcode::NxDomain => 3,
+            Rcode::NotImplemented => 4,
+            Rcode::Refused => 5,
+            Rcode::YXDomain => 6,
+            Rcode::YXRRSet => 7,
+            Rcode::NXRRSet => 8,
+            Rcode::NotAuth => 9,
+            Rcode::NotZone => 10,
+            Rcode::Other(x) => x,
         }
     }
+}
+
+/// Mnemonic of a full (extended) RCODE, as dig prints it in `status:`
+pub(crate) fn rcode_name(code: u16) -> String {
+    let name = match code {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET",
+        8 => "NXRRSET",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        16 => "BADVERS",
+        23 => "BADCOOKIE",
+        code => return format!("RCODE{}", code),
+    };
+    name.to_string()
+}
+
+impl Display for Rcode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&rcode_name(u8::from(*self).into()))
+    }
+}
 
-    fn is_tcp(&self) -> bool {
-        self.0.is_some()
+/// Kind of query in the OPCODE header field
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Opcode {
+    Query,
+    /// Inverse query, obsoleted by RFC 3425
+    IQuery,
+    Status,
+    Notify,
+    Update,
+    Unknown(u8),
+}
+
+impl From<u8> for Opcode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Opcode::Query,
+            1 => Opcode::IQuery,
+            2 => Opcode::Status,
+            4 => Opcode::Notify,
+            5 => Opcode::Update,
+            x => Opcode::Unknown(x),
+        }
     }
 }
 
-impl DekuWrite<(Endian, bool)> for TcpHeaderField {
-    fn write(&self, output: &mut BitVec<u8, Msb0>, is_tcp: (Endian, bool)) -> Result<(), DekuError> {
-        if is_tcp.1 {
-            self.0.unwrap().write(output, is_tcp.0)?;
+impl From<Opcode> for u8 {
+    fn from(value: Opcode) -> Self {
+        match value {
+            Opcode::Query => 0,
+            Opcode::IQuery => 1,
+            Opcode::Status => 2,
+            Opcode::Notify => 4,
+            Opcode::Update => 5,
+            Opcode::Unknown(x) => x,
         }
-        Ok(())
     }
 }
 
-impl DekuRead<'_, (Endian, bool)> for TcpHeaderField {
-    fn read(input: &BitSlice<u8, Msb0>, is_tcp: (Endian, bool)) -> Result<(&BitSlice<u8, Msb0>, Self), DekuError> {
-        if is_tcp.1 {
-            let (input, value) = u16::read(input, is_tcp.0)?;
-            Ok((input, TcpHeaderField(Some(value))))
-        } else {
-            Ok((input, TcpHeaderField(None)))
+impl Display for Opcode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Opcode::Query => f.write_str("QUERY"),
+            Opcode::IQuery => f.write_str("IQUERY"),
+            Opcode::Status => f.write_str("STATUS"),
+            Opcode::Notify => f.write_str("NOTIFY"),
+            Opcode::Update => f.write_str("UPDATE"),
+            Opcode::Unknown(x) => write!(f, "RESERVED{}", x),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, DekuRead, DekuWrite)]
-#[deku(endian = "big", ctx = "is_tcp: bool")]
+/// Header of a response. Flags the query controls (opcode, RD, CD) start cleared and are copied over with
+/// `DNSHeader::copy_request_flags`; AA starts cleared until we know we're authoritative for the answer.
+pub fn response_header(
+    id: u16,
+    questions: usize,
+    answers: usize,
+    authorities: usize,
+    additionals: usize,
+    truncated: bool,
+    rcode: Rcode,
+) -> DNSHeader {
+    DNSHeader {
+        id,
+        qr: 1,
+        opcode: 0,
+        aa: 0,
+        tc: u8::from(truncated),
+        rd: 0,
+        // We never recurse
+        ra: 0,
+        z: 0,
+        ad: 0,
+        cd: 0,
+        rcode,
+        qdcount: questions as u16,
+        ancount: answers as u16,
+        nscount: authorities as u16,
+        arcount: additionals as u16,
+    }
+}
+
+/// Length of the header on the wire
+pub(crate) const HEADER_LEN: usize = 12;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct DNSHeader {
-    #[deku(ctx = "is_tcp")]
-    tcp_header_field: TcpHeaderField,
-    #[deku(bits = "16")]
-    pub(crate) id: u16,
-    #[deku(bits = "1")]
+    id: u16,
+    // Flags are single bits, the opcode is 4 bits
     qr: u8,
-    #[deku(bits = "4")]
     opcode: u8,
-    #[deku(bits = "1")]
     aa: u8,
-    #[deku(bits = "1")]
-    pub(crate) tc: u8,
-    #[deku(bits = "1")]
+    tc: u8,
     rd: u8,
-    #[deku(bits = "1")]
     ra: u8,
-    #[deku(bits = "3")]
     z: u8,
-    #[deku(endian = "")]
-    pub rcode: Rcode,
-    #[deku(bits = "16")]
+    // Authentic data and checking disabled (RFC 4035 section 3.2)
+    ad: u8,
+    cd: u8,
+    rcode: Rcode,
     qdcount: u16,
-    #[deku(bits = "16")]
-    pub(crate) ancount: u16,
+    ancount: u16,
 
     // Authority count
-    #[deku(bits = "16")]
-    pub(crate) nscount: u16,
+    nscount: u16,
 
     // Additional information count
-    #[deku(bits = "16")]
-    pub(crate) arcount: u16,
+    arcount: u16,
 }
 
 impl DNSHeader {
-    pub(crate) fn update_from_total_msg_len(&mut self, total_msg_len: u16) -> bool {
-        let mut updated = false;
+    pub(crate) fn read(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        let id = reader.u16()?;
+        let [high, low] = reader.array()?;
+        Ok(DNSHeader {
+            id,
+            qr: high >> 7,
+            opcode: (high >> 3) & 0xF,
+            aa: (high >> 2) & 1,
+            tc: (high >> 1) & 1,
+            rd: high & 1,
+            ra: low >> 7,
+            z: (low >> 6) & 1,
+            ad: (low >> 5) & 1,
+            cd: (low >> 4) & 1,
+            rcode: Rcode::from_low_bits(low),
+            qdcount: reader.u16()?,
+            ancount: reader.u16()?,
+            nscount: reader.u16()?,
+            arcount: reader.u16()?,
+        })
+    }
 
-        if total_msg_len > TRUNCATE_BYTES && self.tc == 0 && !self.tcp_header_field.is_tcp() {
-            self.tc = 1;
-            updated = true;
+    /// The header as it goes on the wire, for patching it into a message that's already written
+    pub(crate) fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let high = self.qr << 7 | (self.opcode & 0xF) << 3 | self.aa << 2 | self.tc << 1 | self.rd;
+        let low = self.ra << 7 | self.z << 6 | self.ad << 5 | self.cd << 4 | u8::from(self.rcode) & 0xF;
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..2].copy_from_slice(&self.id.to_be_bytes());
+        bytes[2] = high;
+        bytes[3] = low;
+        for (chunk, count) in bytes[4..].chunks_mut(2).zip([self.qdcount, self.ancount, self.nscount, self.arcount]) {
+            chunk.copy_from_slice(&count.to_be_bytes());
         }
+        bytes
+    }
 
-        if self.tcp_header_field.is_tcp() {
-            self.tcp_header_field.set(total_msg_len);
-            updated = true;
-        }
+    pub(crate) fn write(&self, writer: &mut Writer<'_>) {
+        writer.bytes(&self.to_bytes());
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn set_id(&mut self, id: u16) {
+        self.id = id;
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.qr == 1
+    }
+
+    pub fn set_response(&mut self, response: bool) {
+        self.qr = u8::from(response);
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        Opcode::from(self.opcode)
+    }
+
+    pub fn set_opcode(&mut self, opcode: Opcode) {
+        self.opcode = u8::from(opcode) & 0xF;
+    }
+
+    pub fn authoritative(&self) -> bool {
+        self.aa == 1
+    }
+
+    pub fn set_authoritative(&mut self, authoritative: bool) {
+        self.aa = u8::from(authoritative);
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.tc == 1
+    }
+
+    pub fn set_truncated(&mut self, truncated: bool) {
+        self.tc = u8::from(truncated);
+    }
+
+    pub fn recursion_desired(&self) -> bool {
+        self.rd == 1
+    }
+
+    pub fn set_recursion_desired(&mut self, recursion_desired: bool) {
+        self.rd = u8::from(recursion_desired);
+    }
+
+    pub fn recursion_available(&self) -> bool {
+        self.ra == 1
+    }
+
+    pub fn set_recursion_available(&mut self, recursion_available: bool) {
+        self.ra = u8::from(recursion_available);
+    }
+
+    pub fn authentic_data(&self) -> bool {
+        self.ad == 1
+    }
+
+    pub fn set_authentic_data(&mut self, authentic_data: bool) {
+        self.ad = u8::from(authentic_data);
+    }
+
+    pub fn checking_disabled(&self) -> bool {
+        self.cd == 1
+    }
+
+    pub fn set_checking_disabled(&mut self, checking_disabled: bool) {
+        self.cd = u8::from(checking_disabled);
+    }
+
+    pub fn rcode(&self) -> Rcode {
+        self.rcode
+    }
+
+    pub fn set_rcode(&mut self, rcode: Rcode) {
+        self.rcode = rcode;
+    }
+
+    pub fn qdcount(&self) -> u16 {
+        self.qdcount
+    }
+
+    pub fn ancount(&self) -> u16 {
+        self.ancount
+    }
+
+    pub fn nscount(&self) -> u16 {
+        self.nscount
+    }
+
+    pub fn arcount(&self) -> u16 {
+        self.arcount
+    }
+
+    /// Section counts are derived from the sections themselves when writing a message
+    pub(crate) fn set_counts(&mut self, qdcount: u16, ancount: u16, nscount: u16, arcount: u16) {
+        self.qdcount = qdcount;
+        self.ancount = ancount;
+        self.nscount = nscount;
+        self.arcount = arcount;
+    }
 
-        updated
+    /// Echoes the opcode and the RD and CD flags of `query`, as responses have to (RFC 1035 4.1.1, RFC 4035 3.2.2)
+    pub fn copy_request_flags(&mut self, query: &DNSHeader) {
+        self.opcode = query.opcode;
+        self.rd = query.rd;
+        self.cd = query.cd;
     }
 
-    pub(crate) fn message_len_offset(&self) -> usize {
-        if self.tcp_header_field.0.is_some() {
-            2
-        } else {
-            0
+    /// Sets TC if a UDP message of `total_msg_len` bytes doesn't fit in `max_udp_len`. TCP messages are never truncated.
+    pub(crate) fn update_from_total_msg_len(&mut self, total_msg_len: usize, max_udp_len: usize, is_tcp: bool) -> bool {
+        if total_msg_len > max_udp_len && self.tc == 0 && !is_tcp {
+            self.tc = 1;
+            return true;
         }
+        false
+    }
+
+    /// Writes the header like dig does, with the upper bits of the RCODE taken from the OPT record
+    pub(crate) fn fmt_with_extended_rcode(&self, f: &mut Formatter<'_>, extended_rcode: u8) -> std::fmt::Result {
+        let status = rcode_name(u16::from(extended_rcode) << 4 | u16::from(u8::from(self.rcode)));
+        writeln!(f, ";; ->>HEADER<<- opcode: {}, status: {}, id: {}", self.opcode(), status, self.id)?;
+
+        let flags = [
+            ("qr", self.qr),
+            ("aa", self.aa),
+            ("tc", self.tc),
+            ("rd", self.rd),
+            ("ra", self.ra),
+            ("ad", self.ad),
+            ("cd", self.cd),
+        ];
+        let flags: Vec<&str> = flags.iter().filter(|(_, bit)| *bit == 1).map(|(name, _)| *name).collect();
+        write!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags.join(" "),
+            self.qdcount,
+            self.ancount,
+            self.nscount,
+            self.arcount
+        )
+    }
+}
+
+impl Display for DNSHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.fmt_with_extended_rcode(f, 0)
     }
 }
 
-const TRUNCATE_BYTES: u16 = 512;
+/// Maximum UDP message size without EDNS (RFC 1035 4.2.1). Clients can only raise it by sending an OPT record.
+pub(crate) const TRUNCATE_BYTES: u16 = 512;
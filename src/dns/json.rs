@@ -0,0 +1,427 @@
+// JSON representation of DNS messages (RFC 8427)
+//
+// Messages are written with both their decoded fields and their octets in `messageOctetsHEX`. When reading, the
+// octets are used if present, so a message survives the round trip exactly. Otherwise it's encoded from the other
+// members, with each record's RDATA taken from `RDATAHEX`, or else from `rdata<TYPE>` in presentation format.
+
+use crate::{
+    dns::{
+        data::RData,
+        header::{self, DNSHeader, Opcode, Rcode},
+        question::{DNSQuestion, Question},
+        record::{class_name, parse_rdata_from_rtype, DNSRecord, CLASS_IN},
+        response::Response,
+        rtypes::RType,
+    },
+    utils::{from_hex, to_hex},
+};
+use anyhow::{anyhow, Context};
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::BTreeMap, sync::OnceLock};
+
+/// A message object. Every member is optional when reading; missing flags and counts are zero.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JsonMessage {
+    #[serde(rename = "ID")]
+    pub id: u16,
+    #[serde(rename = "QR", with = "flag")]
+    pub qr: bool,
+    #[serde(rename = "Opcode")]
+    pub opcode: u8,
+    #[serde(rename = "AA", with = "flag")]
+    pub aa: bool,
+    #[serde(rename = "TC", with = "flag")]
+    pub tc: bool,
+    #[serde(rename = "RD", with = "flag")]
+    pub rd: bool,
+    #[serde(rename = "RA", with = "flag")]
+    pub ra: bool,
+    #[serde(rename = "AD", with = "flag")]
+    pub ad: bool,
+    #[serde(rename = "CD", with = "flag")]
+    pub cd: bool,
+    #[serde(rename = "RCODE")]
+    pub rcode: u8,
+    #[serde(rename = "QDCOUNT")]
+    pub qdcount: u16,
+    #[serde(rename = "ANCOUNT")]
+    pub ancount: u16,
+    #[serde(rename = "NSCOUNT")]
+    pub nscount: u16,
+    #[serde(rename = "ARCOUNT")]
+    pub arcount: u16,
+
+    // Shorthand for the first question (RFC 8427 section 2.3)
+    #[serde(rename = "QNAME", skip_serializing_if = "Option::is_none")]
+    pub qname: Option<String>,
+    #[serde(rename = "QTYPE", skip_serializing_if = "Option::is_none")]
+    pub qtype: Option<u16>,
+    #[serde(rename = "QTYPEname", skip_serializing_if = "Option::is_none")]
+    pub qtype_name: Option<String>,
+    #[serde(rename = "QCLASS", skip_serializing_if = "Option::is_none")]
+    pub qclass: Option<u16>,
+    #[serde(rename = "QCLASSname", skip_serializing_if = "Option::is_none")]
+    pub qclass_name: Option<String>,
+
+    #[serde(rename = "questionRRs", skip_serializing_if = "Vec::is_empty")]
+    pub question_rrs: Vec<JsonRecord>,
+    #[serde(rename = "answerRRs", skip_serializing_if = "Vec::is_empty")]
+    pub answer_rrs: Vec<JsonRecord>,
+    #[serde(rename = "authorityRRs", skip_serializing_if = "Vec::is_empty")]
+    pub authority_rrs: Vec<JsonRecord>,
+    #[serde(rename = "additionalRRs", skip_serializing_if = "Vec::is_empty")]
+    pub additional_rrs: Vec<JsonRecord>,
+
+    #[serde(rename = "messageOctetsHEX", skip_serializing_if = "Option::is_none")]
+    pub message_octets_hex: Option<String>,
+}
+
+/// A resource record object. Questions only have the name, type and class.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JsonRecord {
+    #[serde(rename = "NAME")]
+    pub name: String,
+    #[serde(rename = "TYPE")]
+    pub rtype: u16,
+    #[serde(rename = "TYPEname", skip_serializing_if = "Option::is_none")]
+    pub type_name: Option<String>,
+    #[serde(rename = "CLASS")]
+    pub class: u16,
+    #[serde(rename = "CLASSname", skip_serializing_if = "Option::is_none")]
+    pub class_name: Option<String>,
+    #[serde(rename = "TTL", skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    #[serde(rename = "RDLENGTH", skip_serializing_if = "Option::is_none")]
+    pub rdlength: Option<u16>,
+    #[serde(rename = "RDATAHEX", skip_serializing_if = "Option::is_none")]
+    pub rdata_hex: Option<String>,
+    /// RDATA in presentation format, keyed `rdata` and the type name (`rdataA`, `rdataMX`, ...). Anything else
+    /// that isn't a known member lands here too and is ignored.
+    #[serde(flatten)]
+    pub rdata: BTreeMap<String, serde_json::Value>,
+}
+
+/// RFC 8427 makes the flags booleans, but some tools write them as 0 and 1, so both are read
+mod flag {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(flag: &bool, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bool(*flag)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Flag {
+            Bool(bool),
+            Int(u8),
+        }
+        match Flag::deserialize(deserializer)? {
+            Flag::Bool(flag) => Ok(flag),
+            Flag::Int(0) => Ok(false),
+            Flag::Int(1) => Ok(true),
+            Flag::Int(n) => Err(D::Error::custom(format!("Flag must be 0 or 1, got {}", n))),
+        }
+    }
+}
+
+fn rdata_key(rtype: RType) -> String {
+    format!("rdata{}", rtype)
+}
+
+impl From<&DNSQuestion> for JsonRecord {
+    fn from(question: &DNSQuestion) -> Self {
+        JsonRecord {
+            name: question.qname.to_fqdn(),
+            rtype: u16::from(question.qtype),
+            type_name: Some(question.qtype.to_string()),
+            class: question.qclass,
+            class_name: Some(class_name(question.qclass)),
+            ..JsonRecord::default()
+        }
+    }
+}
+
+impl From<&DNSRecord> for JsonRecord {
+    fn from(record: &DNSRecord) -> Self {
+        // Only fails for RDATA too long to be sent, which then can't be given in hex either
+        let octets = record.rdata.to_bytes().ok();
+        JsonRecord {
+            name: record.name.to_fqdn(),
+            rtype: u16::from(record.rtype),
+            type_name: Some(record.rtype.to_string()),
+            class: record.class,
+            class_name: Some(class_name(record.class)),
+            ttl: Some(record.ttl),
+            rdlength: octets.as_ref().map(|octets| octets.len() as u16),
+            rdata_hex: octets.as_deref().map(to_hex),
+            rdata: BTreeMap::from([(rdata_key(record.rtype), record.rdata.to_string().into())]),
+        }
+    }
+}
+
+impl TryFrom<&JsonRecord> for DNSQuestion {
+    type Error = anyhow::Error;
+
+    fn try_from(question: &JsonRecord) -> Result<Self, Self::Error> {
+        Ok(DNSQuestion {
+            qname: question.name.parse().context("Invalid NAME")?,
+            qtype: RType::from(question.rtype),
+            qclass: question.class,
+        })
+    }
+}
+
+impl TryFrom<&JsonRecord> for DNSRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(record: &JsonRecord) -> Result<Self, Self::Error> {
+        let rtype = RType::from(record.rtype);
+        let rdata = match (&record.rdata_hex, record.rdata.get(&rdata_key(rtype))) {
+            (Some(hex), _) => RData::from_bytes(&from_hex(hex)?, rtype).with_context(|| anyhow!("Invalid {} RDATAHEX", rtype))?,
+            (None, Some(serde_json::Value::String(rdata))) => parse_rdata_from_rtype(rdata, rtype)?,
+            (None, Some(_)) => return Err(anyhow!("{} should be a string", rdata_key(rtype))),
+            (None, None) => return Err(anyhow!("{} record without RDATAHEX or {}", rtype, rdata_key(rtype))),
+        };
+        Ok(DNSRecord {
+            name: record.name.parse().context("Invalid NAME")?,
+            rtype,
+            class: record.class,
+            ttl: record.ttl.unwrap_or(0),
+            rdata,
+        })
+    }
+}
+
+impl From<&Question> for JsonMessage {
+    fn from(message: &Question) -> Self {
+        let header = message.header();
+        let first = message.questions.first();
+        let records = |records: &[DNSRecord]| records.iter().map(JsonRecord::from).collect();
+        JsonMessage {
+            id: header.id(),
+            qr: header.is_response(),
+            opcode: u8::from(header.opcode()),
+            aa: header.authoritative(),
+            tc: header.truncated(),
+            rd: header.recursion_desired(),
+            ra: header.recursion_available(),
+            ad: header.authentic_data(),
+            cd: header.checking_disabled(),
+            rcode: header.rcode().into(),
+            qdcount: header.qdcount(),
+            ancount: header.ancount(),
+            nscount: header.nscount(),
+            arcount: header.arcount(),
+            qname: first.map(|question| question.qname.to_fqdn()),
+            qtype: first.map(|question| u16::from(question.qtype)),
+            qtype_name: first.map(|question| question.qtype.to_string()),
+            qclass: first.map(|question| question.qclass),
+            qclass_name: first.map(|question| class_name(question.qclass)),
+            question_rrs: message.questions.iter().map(JsonRecord::from).collect(),
+            answer_rrs: records(&message.answer),
+            authority_rrs: records(&message.authority),
+            additional_rrs: records(&message.additional),
+            message_octets_hex: Some(to_hex(&message.octets)),
+        }
+    }
+}
+
+impl JsonMessage {
+    /// The message in wire format. The section counts are those of the sections given, whatever the JSON says.
+    pub fn to_wire(&self) -> anyhow::Result<Vec<u8>> {
+        if let Some(hex) = &self.message_octets_hex {
+            return from_hex(hex).context("Invalid messageOctetsHEX");
+        }
+
+        let questions = match (&self.qname, self.question_rrs.is_empty()) {
+            (Some(qname), true) => vec![DNSQuestion {
+                qname: qname.parse().context("Invalid QNAME")?,
+                qtype: RType::from(self.qtype.unwrap_or(u16::from(RType::A))),
+                qclass: self.qclass.unwrap_or(CLASS_IN),
+            }],
+            _ => self.question_rrs.iter().map(DNSQuestion::try_from).collect::<anyhow::Result<_>>()?,
+        };
+        let records = |records: &[JsonRecord]| records.iter().map(DNSRecord::try_from).collect::<anyhow::Result<Vec<_>>>();
+        let mut response = Response::from_parts(
+            self.header(),
+            questions,
+            records(&self.answer_rrs).context("Invalid answerRRs")?,
+            records(&self.authority_rrs).context("Invalid authorityRRs")?,
+            records(&self.additional_rrs).context("Invalid additionalRRs")?,
+        );
+        Ok(Response::serialize(&mut response)?)
+    }
+
+    fn header(&self) -> DNSHeader {
+        let mut header = header::response_header(self.id, 0, 0, 0, 0, self.tc, Rcode::from_low_bits(self.rcode));
+        header.set_response(self.qr);
+        header.set_opcode(Opcode::from(self.opcode));
+        header.set_authoritative(self.aa);
+        header.set_recursion_desired(self.rd);
+        header.set_recursion_available(self.ra);
+        header.set_authentic_data(self.ad);
+        header.set_checking_disabled(self.cd);
+        header
+    }
+}
+
+impl TryFrom<&JsonMessage> for Question {
+    type Error = anyhow::Error;
+
+    fn try_from(message: &JsonMessage) -> Result<Self, Self::Error> {
+        let octets = message.to_wire()?;
+        Ok(Question::parse(&octets)?)
+    }
+}
+
+impl Serialize for Question {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        JsonMessage::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Question {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let message = JsonMessage::deserialize(deserializer)?;
+        Question::try_from(&message).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Response {
+    /// The message as it would be sent
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let octets = Response::serialize(&mut self.clone()).map_err(ser::Error::custom)?;
+        let message = Question::parse(&octets).map_err(ser::Error::custom)?;
+        message.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Response {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Response::from(Question::deserialize(deserializer)?))
+    }
+}
+
+/// Set `DNS_JSON_LOG=1` to print every query and response to stdout as JSON, one message per line
+fn enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| std::env::var_os("DNS_JSON_LOG").is_some_and(|v| !v.is_empty() && v != "0"))
+}
+
+/// Logs a message as it was sent or received
+pub(crate) fn log(octets: &[u8]) {
+    if !enabled() {
+        return;
+    }
+    let json = Question::parse(octets)
+        .map_err(anyhow::Error::from)
+        .and_then(|message| Ok(serde_json::to_string(&message)?));
+    match json {
+        Ok(json) => println!("{}", json),
+        Err(err) => eprintln!("Failed to log message as JSON: {err:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::name::DNSName;
+    use serde_json::json;
+
+    fn response() -> Response {
+        let question = DNSQuestion::new(DNSName::from_url("www.example.com"), RType::A);
+        let answer = vec![DNSRecord::try_from("www.example.com 300 A 10.0.0.1").unwrap()];
+        let authority = vec![DNSRecord::try_from("example.com NS ns1.example.com").unwrap()];
+        let mut response = Response::new(7, question, answer, authority, Vec::new(), false, Rcode::NoError);
+        response.header_mut().set_authoritative(true);
+        response
+    }
+
+    #[test]
+    fn test_serialize() {
+        let octets = Response::serialize(&mut response()).unwrap();
+        let json = serde_json::to_value(response()).unwrap();
+
+        assert_eq!(json["ID"], 7);
+        assert_eq!(json["QR"], true);
+        assert_eq!(json["AA"], true);
+        assert_eq!(json["RD"], false);
+        assert_eq!(json["Opcode"], 0);
+        assert_eq!(json["RCODE"], 0);
+        assert_eq!(json["ANCOUNT"], 1);
+        assert_eq!(json["QNAME"], "www.example.com.");
+        assert_eq!(json["QTYPEname"], "A");
+        assert_eq!(json["QCLASSname"], "IN");
+        assert_eq!(
+            json["answerRRs"],
+            json!([{
+                "NAME": "www.example.com.",
+                "TYPE": 1,
+                "TYPEname": "A",
+                "CLASS": 1,
+                "CLASSname": "IN",
+                "TTL": 300,
+                "RDLENGTH": 4,
+                "RDATAHEX": "0A000001",
+                "rdataA": "10.0.0.1",
+            }])
+        );
+        assert_eq!(json["authorityRRs"][0]["rdataNS"], "ns1.example.com.");
+        assert!(json.get("additionalRRs").is_none());
+        assert_eq!(json["messageOctetsHEX"], to_hex(&octets));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let octets = Response::serialize(&mut response()).unwrap();
+        let json = serde_json::to_string(&response()).unwrap();
+
+        let message: Question = serde_json::from_str(&json).unwrap();
+        assert_eq!(message.octets, octets);
+        let mut response: Response = serde_json::from_str(&json).unwrap();
+        assert_eq!(Response::serialize(&mut response).unwrap(), octets);
+    }
+
+    #[test]
+    fn test_encode_fields() {
+        let octets = Response::serialize(&mut response()).unwrap();
+        let mut json = serde_json::to_value(response()).unwrap();
+        json.as_object_mut().unwrap().remove("messageOctetsHEX");
+
+        let message: JsonMessage = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(message.to_wire().unwrap(), octets);
+
+        // Presentation format is enough without the hex
+        for record in json["answerRRs"].as_array_mut().unwrap() {
+            record.as_object_mut().unwrap().remove("RDATAHEX");
+        }
+        let message: JsonMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(message.to_wire().unwrap(), octets);
+    }
+
+    #[test]
+    fn test_minimal_query() {
+        let json = json!({"ID": 1, "RD": 1, "QNAME": "example.com", "QTYPE": 28});
+        let message: Question = serde_json::from_value(json).unwrap();
+        assert_eq!(message.header().id(), 1);
+        assert!(message.header().recursion_desired());
+        assert!(!message.header().is_response());
+        assert_eq!(message.questions, [DNSQuestion::new(DNSName::from_url("example.com"), RType::AAAA)]);
+
+        assert!(serde_json::from_value::<Question>(json!({"QR": 2})).is_err());
+        assert!(serde_json::from_value::<Question>(json!({"answerRRs": [{"NAME": "example.com", "TYPE": 1}]})).is_err());
+        assert!(serde_json::from_value::<Question>(json!({"messageOctetsHEX": "00"})).is_err());
+    }
+
+    #[test]
+    fn test_unassigned_rcode() {
+        let message: Question = serde_json::from_value(json!({"ID": 1, "QR": 1, "RCODE": 11})).unwrap();
+        assert_eq!(message.header().rcode(), Rcode::Other(11));
+        assert_eq!(message.octets[3], 11);
+        assert_eq!(serde_json::to_value(&message).unwrap()["RCODE"], 11);
+        assert!(message.to_string().contains("status: RCODE11"));
+    }
+}
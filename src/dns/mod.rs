@@ -1,10 +1,18 @@
 mod compression;
+pub(crate) mod cookie;
 pub(crate) mod data;
+pub(crate) mod ecs;
+pub(crate) mod ede;
+pub(crate) mod edns;
 pub(crate) mod header;
+pub(crate) mod json;
 pub(crate) mod name;
+pub(crate) mod padding;
 pub(crate) mod question;
 pub(crate) mod record;
 pub(crate) mod response;
 pub(crate) mod rtypes;
+pub(crate) mod svcb;
 pub mod text;
-mod builders;
+pub(crate) mod tsig;
+pub(crate) mod wire;
@@ -1,48 +1,186 @@
-use crate::dns::{compression::CompressedRef, rtypes::RType};
-use deku::{
-    bitvec::{BitSlice, BitVec, Msb0},
-    ctx::Endian,
-    prelude::*,
+use crate::dns::{
+    text::{escape, unescape},
+    wire::{Reader, WireError, Writer},
 };
-use std::{borrow::Borrow, cmp::Ordering, io::Write, ops::Deref};
+use anyhow::anyhow;
+use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    fmt::{Debug, Display, Formatter},
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    ops::Deref,
+    str::FromStr,
+};
+
+/// Longest label in octets (RFC 1035 section 2.3.4). Lengths 64 to 191 would clash with the pointer and
+/// extended label bits.
+pub(crate) const MAX_LABEL_LEN: usize = 63;
+/// Longest name in octets on the wire, counting the length bytes and the root label
+pub(crate) const MAX_NAME_LEN: usize = 255;
+
+/// A single label. Any byte is allowed on the wire (RFC 2181 section 11), so labels are raw bytes rather than
+/// strings. Comparison and hashing ignore ASCII case (RFC 4343).
+#[derive(Clone)]
+pub struct Label(Vec<u8>);
+
+impl Label {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The label as text, if it's valid UTF-8. Unlike `to_string` nothing is escaped.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+}
+
+impl From<&str> for Label {
+    fn from(value: &str) -> Self {
+        Label(value.as_bytes().to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Label {
+    fn from(value: Vec<u8>) -> Self {
+        Label(value)
+    }
+}
+
+impl PartialEq for Label {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl Eq for Label {}
+
+impl PartialEq<&str> for Label {
+    fn eq(&self, other: &&str) -> bool {
+        self.0.eq_ignore_ascii_case(other.as_bytes())
+    }
+}
+
+impl Hash for Label {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.0.len());
+        self.0.iter().for_each(|b| state.write_u8(b.to_ascii_lowercase()));
+    }
+}
+
+impl Display for Label {
+    /// Presentation format: dots, quotes and backslashes are escaped with a backslash, spaces and anything
+    /// unprintable as `\DDD`
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut output = String::new();
+        for &byte in &self.0 {
+            match byte {
+                b' ' => output.push_str("\\032"),
+                _ => escape(byte, b".\"();", &mut output),
+            }
+        }
+        f.write_str(&output)
+    }
+}
+
+impl Debug for Label {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
-pub struct DNSName(pub Vec<String>);
+pub struct DNSName(pub Vec<Label>);
 
-impl ToString for DNSName {
-    fn to_string(&self) -> String {
-        self.0.join(".")
+impl Display for DNSName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let labels: Vec<String> = self.0.iter().map(Label::to_string).collect();
+        f.write_str(&labels.join("."))
+    }
+}
+
+impl FromStr for DNSName {
+    type Err = anyhow::Error;
+
+    /// Parses a name in presentation format, where `\.` is a dot inside a label and `\DDD` is any byte.
+    /// The trailing dot is optional and "." is the root.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "." {
+            return Ok(DNSName(Vec::new()));
+        }
+
+        let mut labels = Vec::new();
+        let mut label = Vec::new();
+        let mut chars = s.bytes();
+        while let Some(c) = chars.next() {
+            match c {
+                b'\\' => label.push(unescape(&mut chars)?),
+                b'.' if label.is_empty() => return Err(anyhow!("Empty label in '{}'", s)),
+                b'.' => labels.push(Label(std::mem::take(&mut label))),
+                c => label.push(c),
+            }
+        }
+        if !label.is_empty() {
+            labels.push(Label(label));
+        }
+        if labels.is_empty() {
+            return Err(anyhow!("Empty domain name"));
+        }
+
+        let name = DNSName(labels);
+        if let Some(label) = name.0.iter().find(|l| l.as_bytes().len() > MAX_LABEL_LEN) {
+            return Err(anyhow!("Label '{}' in '{}' is longer than {} octets", label, s, MAX_LABEL_LEN));
+        }
+        if name.wire_len() > MAX_NAME_LEN {
+            return Err(anyhow!("'{}' is {} octets, longer than {}", s, name.wire_len(), MAX_NAME_LEN));
+        }
+        Ok(name)
     }
 }
 
 impl DNSName {
+    /// Shorthand for names that are known to be valid
+    #[cfg(test)]
     pub(crate) fn from_url(s: &str) -> Self {
-        let parts = s
-            .split('.')
-            .filter_map(|a| match a.to_string() {
-                x if x.is_empty() => None,
-                x => Some(x),
-            })
-            .collect();
-        DNSName(parts)
-    }
-
-    pub(crate) fn from_raw_string(s: &str) -> Self {
-        let s = s.as_bytes();
-        let mut parts = Vec::new();
-
-        for p in s.chunks(u8::MAX as usize) {
-            let p = String::from_utf8(p.to_vec()).unwrap();
-            parts.push(p.to_string());
-        }
-        DNSName(parts)
+        s.parse().unwrap()
+    }
+
+    /// Name used for reverse lookups of `ip`: 1.0.0.10.in-addr.arpa for 10.0.0.1, and one label per nibble
+    /// under ip6.arpa for IPv6 (RFC 3596 section 2.5)
+    pub fn reverse_pointer(ip: IpAddr) -> Self {
+        let mut labels: Vec<Label> = match ip {
+            IpAddr::V4(ip) => ip.octets().iter().rev().map(|octet| Label::from(octet.to_string().as_str())).collect(),
+            IpAddr::V6(ip) => ip
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|byte| [byte & 0xF, byte >> 4])
+                .map(|nibble| Label::from(format!("{:x}", nibble).as_str()))
+                .collect(),
+        };
+        let suffix: &[&str] = match ip {
+            IpAddr::V4(_) => &["in-addr", "arpa"],
+            IpAddr::V6(_) => &["ip6", "arpa"],
+        };
+        labels.extend(suffix.iter().copied().map(Label::from));
+        DNSName(labels)
     }
 
     fn len(&self) -> usize {
         self.0.len()
     }
 
-    fn getrev(&self, idx: usize) -> &str {
+    /// Presentation format with the trailing dot, as in master files. The root is ".".
+    pub fn to_fqdn(&self) -> String {
+        format!("{}.", self)
+    }
+
+    /// Uncompressed size on the wire: a length byte per label plus the terminating root label
+    pub(crate) fn wire_len(&self) -> usize {
+        self.0.iter().map(|l| l.as_bytes().len() + 1).sum::<usize>() + 1
+    }
+
+    fn getrev(&self, idx: usize) -> &Label {
         &self[self.len() - idx - 1]
     }
 
@@ -51,7 +189,7 @@ impl DNSName {
         let len = self.len().min(other.len());
         let mut i = 0;
         while i < len {
-            if !self.getrev(i).eq_ignore_ascii_case(other.getrev(i)) {
+            if self.getrev(i) != other.getrev(i) {
                 break;
             }
             i += 1;
@@ -69,97 +207,102 @@ impl DNSName {
 }
 
 impl Deref for DNSName {
-    type Target = [String];
+    type Target = [Label];
 
     fn deref(&self) -> &Self::Target {
         self.0.as_slice()
     }
 }
 
-impl Borrow<[String]> for DNSName {
-    fn borrow(&self) -> &[String] {
+impl Borrow<[Label]> for DNSName {
+    fn borrow(&self) -> &[Label] {
         self.0.as_slice()
     }
 }
 
-impl DekuWrite<DNSNameCtxRtype> for DNSName {
-    fn write(&self, output: &mut BitVec<u8, Msb0>, ctx: DNSNameCtxRtype) -> Result<(), DekuError> {
+impl DNSName {
+    /// Writes the name, replacing the longest suffix that was already written with a pointer to it if `compress`.
+    /// `compress` also says whether later names may point into this one. Owner names and question names always can;
+    /// names inside RDATA only for the types listed in RFC 3597 section 4.
+    pub(crate) fn write(&self, writer: &mut Writer<'_>, compress: bool) -> Result<(), WireError> {
+        if self.wire_len() > MAX_NAME_LEN {
+            return Err(WireError::InvalidParam(format!("Name '{}' is longer than {} octets", self, MAX_NAME_LEN)));
+        }
         for (index, label) in self.0.iter().enumerate() {
             let label_bytes = label.as_bytes();
-            let label_len = label_bytes.len() as u8;
-
-            if ctx.rtype.supports_compression() {
-                if let Some(ptrindex) = ctx.compression.query(self, index) {
-                    let msg = Label::Pointer(ptrindex as u16);
-                    msg.write(output, ())?;
-                    println!("Pointer {} write at index {}", ptrindex, ctx.offset);
+            let label_len = u8::try_from(label_bytes.len())
+                .ok()
+                .filter(|len| *len as usize <= MAX_LABEL_LEN)
+                .ok_or_else(|| WireError::InvalidParam(format!("Label '{}' is longer than {} octets", label, MAX_LABEL_LEN)))?;
+
+            if compress {
+                if let Some(offset) = writer.find_suffix(&self[index..]) {
+                    writer.u16(0xC000 | offset);
                     return Ok(());
                 }
+                writer.add_suffix();
             }
-            let msg = Label::Regular(RegularMsg1 {
-                length: label_len,
-                content: label_bytes,
-            });
-            msg.write(output, ())?;
-        }
-
-        if ctx.is_domain && self.0.last().map_or(true, |a| !a.is_empty()) {
-            output.write_all(&[0]).unwrap();
-        }
-
-        if ctx.rtype.supports_compression() {
-            ctx.compression.add(self.clone(), ctx.offset);
+            writer.u8(label_len);
+            writer.bytes(label_bytes);
         }
+        writer.u8(0);
         Ok(())
     }
-}
 
-impl<'a> DekuRead<'a, DNSNameCtx> for DNSName {
-    fn read(input: &'a BitSlice<u8, Msb0>, _ctx: DNSNameCtx) -> Result<(&'a BitSlice<u8, Msb0>, Self), DekuError>
-    where
-        Self: Sized,
-    {
-        Self::read(input, ())
+    /// Decodes a name, following compression pointers back into the message if there is one
+    pub(crate) fn read(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Self::read_name(reader, true)
+    }
+
+    /// Decodes a name that must not be compressed, like the SVCB target (RFC 9460 section 2.2)
+    pub(crate) fn read_uncompressed(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Self::read_name(reader, false)
     }
-}
 
-impl<'a> DekuRead<'a, ()> for DNSName {
-    fn read(mut input: &'a BitSlice<u8, Msb0>, _ctx: ()) -> Result<(&'a BitSlice<u8, Msb0>, Self), DekuError>
-    where
-        Self: Sized,
-    {
-        let mut pointer_chase_limit: i32 = 10;
+    fn read_name(reader: &mut Reader<'_>, pointers: bool) -> Result<Self, WireError> {
+        // Set once a pointer has been followed. `reader` then stays right after the pointer.
+        let mut jumped: Option<Reader<'_>> = None;
+        // Start of the labels being read, either the name itself or where the last pointer went
+        let mut segment = reader.position();
         let mut decoded = Vec::new();
+        let mut name_len = 1;
+
         loop {
-            let (remaining, label) = match Label::read(input, ()) {
-                Ok((remaining, label)) => (remaining, label),
-                Err(err) => return Err(err),
+            let cursor = match jumped.as_mut() {
+                Some(cursor) => cursor,
+                None => &mut *reader,
             };
-
-            input = remaining;
-
-            match label {
-                Label::Regular(inner) => {
-                    if inner.length == 0 {
-                        break;
+            match cursor.u8()? {
+                // End of domain name
+                0 => break,
+                high if high & 0xC0 == 0xC0 => {
+                    let offset = usize::from(u16::from_be_bytes([high & 0x3F, cursor.u8()?]));
+                    let target = reader
+                        .jump(offset)
+                        .filter(|_| pointers)
+                        .ok_or_else(|| WireError::Parse("Compression pointer outside of a message".into()))?;
+                    // Pointers have to point before the labels they follow, so each jump goes further back and
+                    // loops are impossible
+                    if offset >= segment {
+                        return Err(WireError::Parse(format!("Compression pointer to {} doesn't point backwards", offset)));
                     }
-                    let label = String::from_utf8(inner.content.to_vec()).unwrap();
-                    decoded.push(label);
-                } // End of domain name
-                Label::Pointer(offset) => {
-                    if offset as usize >= input.len() || pointer_chase_limit == 0 {
-                        return Err(DekuError::Parse("Invalid compression pointer offset".into()));
+                    jumped = Some(target);
+                    segment = offset;
+                }
+                len if len as usize > MAX_LABEL_LEN => {
+                    return Err(WireError::Parse(format!("Label length {} is longer than {}", len, MAX_LABEL_LEN)));
+                }
+                len => {
+                    name_len += len as usize + 1;
+                    if name_len > MAX_NAME_LEN {
+                        return Err(WireError::Parse(format!("Name is longer than {} octets", MAX_NAME_LEN)));
                     }
-
-                    let (_, label_bytes) = input.split_at(offset as usize * 8);
-
-                    input = label_bytes;
-                    pointer_chase_limit -= 1;
+                    decoded.push(Label(cursor.bytes(len as usize)?.to_vec()));
                 }
             }
         }
 
-        Ok((input, DNSName(decoded)))
+        Ok(DNSName(decoded))
     }
 }
 
@@ -171,101 +314,6 @@ pub enum NameCmp {
     Different,
 }
 
-#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
-struct RegularMsg1<'a> {
-    length: u8,
-    #[deku(count = "length")]
-    content: &'a [u8],
-}
-
-#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
-#[deku(type = "u8", bits = "2", endian = "big")]
-enum Label<'a> {
-    #[deku(id = "0b11")]
-    Pointer(#[deku(bits = "14")] u16),
-    #[deku(id_pat = "_")]
-    Regular(#[deku(endian = "")] RegularMsg1<'a>),
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    use deku::bitvec::BitView;
-    use std::vec::Vec;
-
-    #[test]
-    fn test_regular_msg1() {
-        let input = [5, 1, 2, 3, 4, 5];
-        let expected = RegularMsg1 {
-            length: 5,
-            content: &[1, 2, 3, 4, 5],
-        };
-
-        let (_rest, result) = RegularMsg1::from_bytes((&input, 0)).unwrap();
-        assert_eq!(result, expected);
-
-        let output = result.to_bytes().unwrap();
-        assert_eq!(output, input);
-    }
-
-    #[test]
-    fn test_label_pointer() {
-        let input = [0b1101_0000, 0x23];
-        let expected = Label::Pointer(0x1023);
-
-        let (_rest, result) = <Label>::from_bytes((&input, 0)).unwrap();
-        assert_eq!(result, expected);
-
-        let output = result.to_bytes().unwrap();
-        assert_eq!(output, input);
-    }
-
-    #[test]
-    fn test_label_regular() {
-        let input: Vec<u8> = vec![0b1100_1001, 0xAE, 5, 1, 2, 3, 4, 5];
-        let expected_first = Label::Pointer(0x09AE);
-        let expected_second = Label::Regular(RegularMsg1 {
-            length: 5,
-            content: &[1, 2, 3, 4, 5],
-        });
-
-        let (_rest, (first, second)) = <(Label, Label)>::read(input.view_bits(), ()).unwrap();
-        assert_eq!(first, expected_first);
-        assert_eq!(second, expected_second);
-
-        let mut output = BitVec::new();
-        (first, second).write(&mut output, ()).unwrap();
-        assert_eq!(output, input.view_bits::<Msb0>());
-    }
-}
-
-type DNSNameCtx = (Endian, usize, CompressedRef);
-// pub type DNSNameCtxRtype = (Endian, usize, CompressedRef, RType);
-
-// Convert DNSNameCtxRtype to a struct with appropriate new method
-impl DNSNameCtxRtype {
-    pub fn new(endian: Endian, offset: usize, compression: CompressedRef, rtype: RType, is_domain: bool) -> Self {
-        Self {endian, offset, compression, rtype, is_domain}
-    }
-}
-
-// Implement from tuple
-impl From<(Endian, usize, CompressedRef, RType, bool)> for DNSNameCtxRtype {
-    fn from(tuple: (Endian, usize, CompressedRef, RType, bool)) -> Self {
-        Self::new(tuple.0, tuple.1, tuple.2, tuple.3, tuple.4)
-    }
-}
-
-#[derive(Clone, PartialEq)]
-pub struct DNSNameCtxRtype {
-    pub endian: Endian,
-    pub offset: usize,
-    pub compression: CompressedRef,
-    pub rtype: RType,
-    pub is_domain: bool,
-}
-
 #[cfg(test)]
 mod domain_tests {
     use super::*;
@@ -313,3 +361,148 @@ mod domain_tests {
         assert_eq!(cmp, NameCmp::Superdomain);
     }
 }
+
+#[cfg(test)]
+mod label_tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_label() {
+        let input = [2, 0xFF, b'.', 3, b'c', b'o', b'm', 0];
+        let mut reader = Reader::new(&input);
+        let name = DNSName::read(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(name.0[0].as_bytes(), [0xFF, b'.']);
+        assert_eq!(name.to_string(), "\\255\\..com");
+
+        assert_eq!(DNSName::from_str(&name.to_string()).unwrap(), name);
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let upper = DNSName::from_url("WWW.Example.COM");
+        let lower = DNSName::from_url("www.example.com");
+        assert_eq!(upper, lower);
+        assert_eq!(upper.cmp(&lower), NameCmp::Equal);
+
+        let hash = |name: &DNSName| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            name.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&upper), hash(&lower));
+        // Case is kept for output
+        assert_eq!(upper.to_string(), "WWW.Example.COM");
+    }
+
+    #[test]
+    fn test_parse_escapes() {
+        let name = DNSName::from_str("a\\.b\\032c.example.com.").unwrap();
+        assert_eq!(name.0[0].as_bytes(), b"a.b c");
+        assert_eq!(name.len(), 3);
+
+        assert!(DNSName::from_str("a..example.com").is_err());
+        assert!(DNSName::from_str("a\\2.com").is_err());
+        assert!(DNSName::from_str("").is_err());
+    }
+}
+
+#[cfg(test)]
+mod length_tests {
+    use super::*;
+    use crate::dns::record::DNSRecord;
+
+    #[test]
+    fn test_parse_limits() {
+        let label = "a".repeat(MAX_LABEL_LEN);
+        assert!(DNSName::from_str(&format!("{}.com", label)).is_ok());
+        assert!(DNSName::from_str(&format!("a{}.com", label)).is_err());
+
+        // 4 labels of 63 plus their length bytes and the root is 257 octets
+        let long = [label.as_str(); 4].join(".");
+        assert!(DNSName::from_str(&long).is_err());
+        assert!(DNSName::from_str(&long[2..]).is_ok());
+
+        assert!(DNSRecord::try_from(format!("{} A 10.0.0.1", long).as_str()).is_err());
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        // Length bytes 64 to 191 aren't valid labels or pointers
+        for len in [64u8, 0x80] {
+            let mut input = vec![len];
+            input.extend(vec![b'a'; len as usize]);
+            input.push(0);
+            assert!(DNSName::read(&mut Reader::new(&input)).is_err());
+        }
+
+        let mut input: Vec<u8> = (0..5).flat_map(|_| std::iter::once(63).chain([b'a'; 63])).collect();
+        input.push(0);
+        assert!(DNSName::read(&mut Reader::new(&input)).is_err());
+    }
+
+    #[test]
+    fn test_write_limits() {
+        let name = DNSName(vec![Label::from("a".repeat(64).as_str())]);
+        assert!(name.write(&mut Writer::new(&mut Vec::new()), false).is_err());
+    }
+}
+
+#[cfg(test)]
+mod pointer_tests {
+    use super::*;
+
+    fn read_at(message: &[u8], offset: usize) -> Result<(usize, DNSName), WireError> {
+        let mut reader = Reader::new(message);
+        reader.bytes(offset)?;
+        let name = DNSName::read(&mut reader)?;
+        Ok((reader.remaining(), name))
+    }
+
+    #[test]
+    fn test_pointer_from_message_start() {
+        // "example.com" at 2, then "www" and a pointer to it at 15, followed by 2 more bytes
+        let message = b"\xAA\xBB\x07example\x03com\x00\x03www\xC0\x02\x01\x02";
+        let (rest, name) = read_at(message, 15).unwrap();
+        assert_eq!(name, DNSName::from_url("www.example.com"));
+        // Reading continues right after the pointer
+        assert_eq!(rest, 2);
+
+        let (rest, name) = read_at(message, 2).unwrap();
+        assert_eq!(name, DNSName::from_url("example.com"));
+        assert_eq!(rest, 8);
+    }
+
+    #[test]
+    fn test_bad_pointers() {
+        // Forward pointer
+        assert!(read_at(b"\xC0\x02\x03com\x00", 0).is_err());
+        // Pointer to itself
+        assert!(read_at(b"\x00\xC0\x01", 1).is_err());
+        // Loop: the name at 0 points to 2, which points back to 0
+        assert!(read_at(b"\x01a\xC0\x00", 0).is_err());
+        // No message to point into
+        assert!(DNSName::read(&mut Reader::detached(b"\x03www\xC0\x00")).is_err());
+        // Pointer where names can't be compressed
+        let mut reader = Reader::new(b"\x00\x03www\xC0\x00");
+        reader.u8().unwrap();
+        assert!(DNSName::read_uncompressed(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_pointer_offset_bits() {
+        // All 14 bits of the pointer are the offset: 0xD023 points to 0x1023
+        let mut message = vec![0; 0x1023];
+        message.extend(b"\x01a\x00\xD0\x23\x05");
+        let (rest, name) = read_at(&message, 0x1026).unwrap();
+        assert_eq!(name, DNSName::from_url("a"));
+        assert_eq!(rest, 1);
+
+        let mut output = Vec::new();
+        let mut writer = Writer::new(&mut output).with_compression(true);
+        writer.bytes(&message[..0x1023]);
+        name.write(&mut writer, true).unwrap();
+        name.write(&mut writer, true).unwrap();
+        assert_eq!(&writer.message()[0x1023..], b"\x01a\x00\xD0\x23");
+    }
+}
@@ -0,0 +1,58 @@
+// EDNS Padding (RFC 7830) with the block-length policy of RFC 8467
+
+/// EDNS option code of padding
+pub const OPTION_PADDING: u16 = 12;
+
+/// RFC 8467 4.1: servers pad responses to a multiple of 468 bytes
+pub const RESPONSE_BLOCK_LEN: u16 = 468;
+
+/// Size of the option code and length that come before the padding bytes
+const OPTION_HEADER_LEN: usize = 4;
+
+/// How messages are padded so their length says less about what's in them. Only worth it on encrypted
+/// transports: in plain DNS the contents are visible anyway.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum PaddingPolicy {
+    #[default]
+    None,
+    /// Pad to the next multiple of this many bytes
+    BlockLength(u16),
+}
+
+impl PaddingPolicy {
+    /// Recommended policy for our responses
+    pub fn responses() -> Self {
+        PaddingPolicy::BlockLength(RESPONSE_BLOCK_LEN)
+    }
+
+    /// Number of zero bytes to put in a padding option so that a message of `len` bytes, which doesn't have the
+    /// option yet, ends on a block boundary. `max_len` caps the padded size, like the UDP payload size does
+    /// (RFC 7830 4). `None` if no padding option should be added.
+    pub(crate) fn padding_len(self, len: usize, max_len: usize) -> Option<usize> {
+        let PaddingPolicy::BlockLength(block) = self else {
+            return None;
+        };
+        let block = usize::from(block.max(1));
+        let padded = (len + OPTION_HEADER_LEN).next_multiple_of(block).min(max_len);
+        padded.checked_sub(len + OPTION_HEADER_LEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padding_len() {
+        let policy = PaddingPolicy::responses();
+        assert_eq!(policy.padding_len(100, usize::MAX), Some(364));
+        assert_eq!(policy.padding_len(464, usize::MAX), Some(0));
+        assert_eq!(policy.padding_len(465, usize::MAX), Some(467));
+        // Capped by the UDP payload size, or no room at all for the option
+        assert_eq!(policy.padding_len(100, 200), Some(96));
+        assert_eq!(policy.padding_len(198, 200), None);
+
+        assert_eq!(PaddingPolicy::BlockLength(128).padding_len(50, usize::MAX), Some(74));
+        assert_eq!(PaddingPolicy::None.padding_len(50, usize::MAX), None);
+    }
+}
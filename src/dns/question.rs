@@ -1,37 +1,211 @@
-use deku::{
-    ctx::Endian,
-    prelude::*,
-};
+use std::fmt::{Display, Formatter};
 
-use crate::dns::{compression::CompressedRef, header::DNSHeader, name::DNSName, record::VecDNSRecord, rtypes::RType};
+use crate::dns::{
+    edns::Edns,
+    header::DNSHeader,
+    name::DNSName,
+    record::{class_name, DNSRecord, VecDNSRecord},
+    rtypes::RType,
+    tsig::{self, KeyRing, RequestTsig},
+    wire::{Reader, WireError, Writer},
+};
 
-#[derive(Debug, PartialEq, Eq, DekuRead, DekuWrite, Clone)]
-#[deku(endian = "big", ctx = "compressed: CompressedRef")]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct DNSQuestion {
-    #[deku(reader = "DNSName::read(deku::input_bits, (Endian::Big, deku::byte_offset, compressed))")]
-    #[deku(writer = "DNSName::write(&self.qname, deku::output, (Endian::Big, deku::byte_offset, compressed, self.qtype, true).into())")]
     pub(crate) qname: DNSName,
     pub(crate) qtype: RType,
-    #[deku(bits = "16")]
-    qclass: u16,
+    pub(crate) qclass: u16,
+}
+
+impl DNSQuestion {
+    pub fn new(qname: DNSName, qtype: RType) -> Self {
+        DNSQuestion { qname, qtype, qclass: 1 }
+    }
+
+    pub(crate) fn read(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        let qname = DNSName::read(reader)?;
+        let qtype = RType::from(reader.u16()?);
+        let qclass = reader.u16()?;
+        Ok(DNSQuestion { qname, qtype, qclass })
+    }
+
+    pub(crate) fn write(&self, writer: &mut Writer<'_>) -> Result<(), WireError> {
+        self.qname.write(writer, true)?;
+        writer.u16(self.qtype.into());
+        writer.u16(self.qclass);
+        Ok(())
+    }
+}
+
+impl Display for DNSQuestion {
+    /// A line of dig's question section, commented out since it isn't a record
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, ";{}\t\t{}\t{}", self.qname.to_fqdn(), class_name(self.qclass), self.qtype)
+    }
 }
 
-#[derive(Debug, PartialEq, DekuRead)]
-#[deku(ctx = "is_tcp: bool")]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Question {
-    #[deku(skip)]
-    #[deku(default = "CompressedRef::new(is_tcp)")]
-    compress: CompressedRef,
-
-    #[deku(ctx = "is_tcp")]
-    pub(crate) header: DNSHeader,
-    #[deku(ctx = "Clone::clone(compress)")]
-    pub(crate) question: DNSQuestion,
-
-    #[deku(ctx = "Clone::clone(compress), header.ancount")]
-    answer: VecDNSRecord,
-    #[deku(ctx = "Clone::clone(compress), header.nscount")]
-    authority: VecDNSRecord,
-    #[deku(ctx = "Clone::clone(compress), header.arcount")]
-    additional: VecDNSRecord,
+    header: DNSHeader,
+    pub(crate) questions: Vec<DNSQuestion>,
+    pub(crate) answer: VecDNSRecord,
+    pub(crate) authority: VecDNSRecord,
+    pub(crate) additional: VecDNSRecord,
+
+    pub(crate) edns: Option<Edns>,
+    pub(crate) tsig: Option<DNSRecord>,
+    /// Where the TSIG record starts in the message, which is where its signature ends
+    tsig_offset: usize,
+    /// The message as it came off the wire
+    pub(crate) octets: Vec<u8>,
+}
+
+impl Question {
+    /// Decodes a message straight from the buffer it was received into. Anything after the message is ignored.
+    pub fn parse(message: &[u8]) -> Result<Self, WireError> {
+        Self::read(&mut Reader::new(message))
+    }
+
+    pub(crate) fn read(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        let start = reader.position();
+        let header = DNSHeader::read(reader)?;
+        let questions = (0..header.qdcount()).map(|_| DNSQuestion::read(reader)).collect::<Result<_, _>>()?;
+        let answer = VecDNSRecord::read(reader, header.ancount())?;
+        let authority = VecDNSRecord::read(reader, header.nscount())?;
+        let mut additional = Vec::with_capacity(header.arcount().into());
+        let mut last_offset = reader.position() - start;
+        for _ in 0..header.arcount() {
+            last_offset = reader.position() - start;
+            additional.push(DNSRecord::read(reader)?);
+        }
+        let additional = VecDNSRecord::from(additional);
+
+        Ok(Question {
+            edns: Edns::from_additional(&additional)?,
+            tsig: tsig::from_additional(&additional)?,
+            tsig_offset: last_offset,
+            octets: reader.since(start).to_vec(),
+            header,
+            questions,
+            answer,
+            authority,
+            additional,
+        })
+    }
+
+    pub fn header(&self) -> &DNSHeader {
+        &self.header
+    }
+
+    /// Checks the TSIG signature against `keys`. `message` is the query as received, which the signature covers.
+    /// `None` for unsigned queries.
+    pub fn verify_tsig(&self, message: &[u8], keys: &KeyRing, now: u64) -> anyhow::Result<Option<RequestTsig>> {
+        self.tsig
+            .as_ref()
+            .map(|record| RequestTsig::verify(message, record, self.tsig_offset, keys, now))
+            .transpose()
+    }
+}
+
+impl Display for Question {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        fmt_message(
+            f,
+            &self.header,
+            self.edns.as_ref(),
+            &self.questions,
+            [&self.answer, &self.authority, &self.additional],
+        )
+    }
+}
+
+/// Writes a whole message the way dig does: header, OPT pseudosection, then every section that isn't empty
+pub(crate) fn fmt_message(
+    f: &mut Formatter<'_>,
+    header: &DNSHeader,
+    edns: Option<&Edns>,
+    questions: &[DNSQuestion],
+    [answer, authority, additional]: [&[DNSRecord]; 3],
+) -> std::fmt::Result {
+    header.fmt_with_extended_rcode(f, edns.map_or(0, |edns| edns.extended_rcode))?;
+    writeln!(f)?;
+    if let Some(edns) = edns {
+        write!(f, "\n;; OPT PSEUDOSECTION:\n{}\n", edns)?;
+    }
+    if !questions.is_empty() {
+        write!(f, "\n;; QUESTION SECTION:\n")?;
+        for question in questions {
+            writeln!(f, "{}", question)?;
+        }
+    }
+    for (section, records) in [("ANSWER", answer), ("AUTHORITY", authority), ("ADDITIONAL", additional)] {
+        // The OPT record was already shown as the pseudosection
+        let records: Vec<&DNSRecord> = records.iter().filter(|record| record.rtype != RType::OPT).collect();
+        if records.is_empty() {
+            continue;
+        }
+        write!(f, "\n;; {} SECTION:\n", section)?;
+        for record in records {
+            writeln!(f, "{}", record)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{header::Rcode, response::Response};
+
+    #[test]
+    fn test_decode_compressed_response() {
+        let records = |records: &[&str]| -> Vec<DNSRecord> { records.iter().map(|r| DNSRecord::try_from(*r).unwrap()).collect() };
+        let answer = records(&["www.example.com CNAME web.example.com", "web.example.com MX 10 mail.example.com"]);
+        let authority = records(&["example.com SOA ns1.example.com hostmaster.example.com 1 7200 3600 1209600 30"]);
+        let additional = records(&["mail.example.com A 10.0.0.2"]);
+
+        let question = DNSQuestion::new(DNSName::from_url("www.example.com"), RType::CNAME);
+        let mut response = Response::new(
+            1,
+            question.clone(),
+            answer.clone(),
+            authority.clone(),
+            additional.clone(),
+            false,
+            Rcode::NoError,
+        );
+        let bytes = response.serialize().unwrap();
+        // Every name after the question is compressed
+        assert!(bytes[33..].windows(2).filter(|w| w[0] == 0xC0).count() >= 6);
+
+        let mut reader = Reader::new(&bytes);
+        let parsed = Question::read(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(parsed.questions, [question]);
+        assert_eq!(*parsed.answer, answer);
+        assert_eq!(*parsed.authority, authority);
+        assert_eq!(*parsed.additional, additional);
+    }
+
+    #[test]
+    fn test_question_count() {
+        let header = |qdcount: u16| [0, 1, 0, 0, 0, qdcount as u8, 0, 0, 0, 0, 0, 0];
+
+        let parsed = Question::parse(&header(0)).unwrap();
+        assert!(parsed.questions.is_empty());
+
+        let mut message = header(2).to_vec();
+        message.extend(b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+        message.extend(b"\xC0\x10\x00\x1C\x00\x01");
+        let mut reader = Reader::new(&message);
+        let parsed = Question::read(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(
+            parsed.questions,
+            [
+                DNSQuestion::new(DNSName::from_url("www.example.com"), RType::A),
+                DNSQuestion::new(DNSName::from_url("example.com"), RType::AAAA)
+            ]
+        );
+    }
 }
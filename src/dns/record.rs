@@ -1,50 +1,109 @@
-use crate::dns::{
-    compression::CompressedRef,
-    data::RData,
-    name::DNSName,
-    rtypes::{ContainsIP, RType},
+use crate::{
+    dns::{
+        data::{is_caa_tag, RData},
+        name::DNSName,
+        rtypes::RType,
+        text::{unescape, DNSText},
+        wire::{Reader, WireError, Writer},
+    },
+    utils::from_hex,
 };
 use anyhow::{anyhow, Context};
-use deku::{
-    bitvec::{BitSlice, BitVec, Msb0},
-    ctx::Endian,
-    prelude::*,
+use std::{
+    fmt::{Display, Formatter},
+    net::{Ipv4Addr, Ipv6Addr},
+    ops::{Deref, DerefMut},
+    str::FromStr,
 };
-use std::{net::Ipv4Addr, ops::Deref, str::FromStr};
 
-#[derive(Debug, PartialEq, Eq, DekuRead, Clone)]
-#[deku(endian = "big", ctx = "compressed: CompressedRef")]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct DNSRecord {
-    #[deku(ctx = "deku::byte_offset, compressed")]
     pub(crate) name: DNSName,
     pub(crate) rtype: RType,
-    #[deku(bits = "16")]
     pub(crate) class: u16,
-    #[deku(bits = "32")]
     pub(crate) ttl: u32,
-
-    #[deku(
-        ctx = "deku::byte_offset, compressed.clone()",
-        reader = "RData::read(deku::rest, ContainsIP::from_class(*rtype))"
-    )]
     pub(crate) rdata: RData,
 }
 
-impl DekuWrite<CompressedRef> for DNSRecord {
-    fn write(&self, output: &mut BitVec<u8, Msb0>, ctx: CompressedRef) -> Result<(), DekuError> {
-        let ctx_q = (Endian::Big, output.len(), ctx.clone(), self.rtype, true);
+impl DNSRecord {
+    pub(crate) fn read(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        let name = DNSName::read(reader)?;
+        let rtype = RType::from(reader.u16()?);
+        let class = reader.u16()?;
+        let ttl = reader.u32()?;
+        let rdata = RData::read(reader, rtype)?;
+        Ok(DNSRecord {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata,
+        })
+    }
 
-        let ctx_a = if self.rtype == RType::TXT {
-            (Endian::Big, output.len(), ctx, self.rtype, false)
-        } else {
-            ctx_q.clone()
-        };
-        self.name.write(output, ctx_q.clone().into())?;
-        self.rtype.write(output, ctx_q.0)?;
-        self.class.write(output, ctx_q.0)?;
-        self.ttl.write(output, ctx_q.0)?;
-        self.rdata.write(output, ctx_a.into())?;
-        Ok(())
+    /// Owner names are always compressed, names inside the RDATA only if the type allows it
+    pub(crate) fn write(&self, writer: &mut Writer<'_>) -> Result<(), WireError> {
+        self.name.write(writer, true)?;
+        writer.u16(self.rtype.into());
+        writer.u16(self.class);
+        writer.u32(self.ttl);
+        self.rdata.write(writer, self.rtype.supports_compression())
+    }
+}
+
+impl DNSRecord {
+    /// Copy of an SOA record for the authority section of a negative answer.
+    /// RFC 2308 section 3 caps its TTL at the SOA MINIMUM field, which is how long resolvers cache the answer.
+    pub fn to_negative_soa(&self) -> DNSRecord {
+        let mut soa = self.clone();
+        if let RData::SOA { minimum, .. } = self.rdata {
+            soa.ttl = soa.ttl.min(minimum);
+        }
+        soa
+    }
+}
+
+impl Display for DNSRecord {
+    /// A master file line, like dig prints in its sections
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            self.name.to_fqdn(),
+            self.ttl,
+            class_name(self.class),
+            self.rtype,
+            self.rdata
+        )
+    }
+}
+
+/// TTL of records given without one
+const DEFAULT_TTL: u32 = 60;
+
+pub(crate) const CLASS_IN: u16 = 1;
+
+/// Mnemonic of a CLASS, or `CLASSnnn` for ones without one (RFC 3597 section 5)
+pub(crate) fn class_name(class: u16) -> String {
+    let name = match class {
+        CLASS_IN => "IN",
+        3 => "CH",
+        4 => "HS",
+        254 => "NONE",
+        255 => "ANY",
+        class => return format!("CLASS{}", class),
+    };
+    name.to_string()
+}
+
+fn parse_class(s: &str) -> Option<u16> {
+    match s.to_ascii_uppercase().as_str() {
+        "IN" => Some(CLASS_IN),
+        "CH" => Some(3),
+        "HS" => Some(4),
+        "NONE" => Some(254),
+        "ANY" => Some(255),
+        s => s.strip_prefix("CLASS")?.parse().ok(),
     }
 }
 
@@ -60,38 +119,152 @@ impl<'a> TryFrom<&'a str> for DNSRecord {
         let name = split
             .next()
             .with_context(|| anyhow!("'name' field doesn't exist for record '{}'", value))?;
-        let rtype = split
-            .next()
-            .with_context(|| anyhow!("'rtype' field doesn't exist for record '{}'", value))?;
+        // TTL and class are optional, in either order (RFC 1035 5.1)
+        let (mut ttl, mut class) = (DEFAULT_TTL, CLASS_IN);
+        let rtype = loop {
+            let field = split
+                .next()
+                .with_context(|| anyhow!("'rtype' field doesn't exist for record '{}'", value))?;
+            if let Ok(value) = field.parse() {
+                ttl = value;
+            } else if let Some(value) = parse_class(field) {
+                class = value;
+            } else {
+                break field;
+            }
+        };
         let rdata = split
             .remainder()
             .with_context(|| anyhow!("'rdata' field doesn't exist for record '{}'", value))?;
 
-        let name = DNSName::from_url(name);
+        let name = DNSName::from_str(name).context("Invalid owner name")?;
         let rtype = RType::from_str(rtype).context("Invalid record type")?;
         let rdata = parse_rdata_from_rtype(rdata, rtype)?;
 
         Ok(Self {
             name,
             rtype,
-            class: 1,
-            ttl: 60,
+            class,
+            ttl,
             rdata,
         })
     }
 }
 
-fn parse_rdata_from_rtype(rdata: &str, rtype: RType) -> anyhow::Result<RData> {
+pub(crate) fn parse_rdata_from_rtype(rdata: &str, rtype: RType) -> anyhow::Result<RData> {
+    if let Some(generic) = rdata.strip_prefix("\\#") {
+        let bytes = parse_generic_rdata(generic)?;
+        // Types we know get decoded (RFC 3597 section 5), anything else is kept as-is
+        return RData::from_bytes(&bytes, rtype).with_context(|| anyhow!("Invalid {} RDATA", rtype));
+    }
+
     let rdata = match rtype {
-        RType::CNAME | RType::NS => RData::Name(DNSName::from_url(rdata)),
-        RType::TXT => RData::Name(DNSName::from_raw_string(rdata)),
-        RType::A => RData::Vec(Ipv4Addr::from_str(rdata)?.octets().to_vec()),
-        _ => return Err(anyhow!("Unsupported record type")),
+        RType::CNAME => RData::CNAME(DNSName::from_str(rdata)?),
+        RType::NS => RData::NS(DNSName::from_str(rdata)?),
+        RType::PTR => RData::PTR(DNSName::from_str(rdata)?),
+        RType::TXT => RData::TXT(DNSText::from_str(rdata).context("Invalid TXT record")?),
+        RType::A => RData::A(Ipv4Addr::from_str(rdata)?),
+        RType::AAAA => RData::AAAA(Ipv6Addr::from_str(rdata)?),
+        RType::MX => {
+            let [preference, exchange] = rdata_fields(rdata)?;
+            RData::MX {
+                preference: preference.parse().context("Invalid MX preference")?,
+                exchange: DNSName::from_str(exchange)?,
+            }
+        }
+        RType::SRV => {
+            let [priority, weight, port, target] = rdata_fields(rdata)?;
+            RData::SRV {
+                priority: priority.parse().context("Invalid SRV priority")?,
+                weight: weight.parse().context("Invalid SRV weight")?,
+                port: port.parse().context("Invalid SRV port")?,
+                target: DNSName::from_str(target)?,
+            }
+        }
+        RType::SOA => {
+            let [mname, rname, serial, refresh, retry, expire, minimum] = rdata_fields(rdata)?;
+            RData::SOA {
+                mname: DNSName::from_str(mname)?,
+                rname: DNSName::from_str(rname)?,
+                serial: serial.parse().context("Invalid SOA serial")?,
+                refresh: refresh.parse().context("Invalid SOA refresh")?,
+                retry: retry.parse().context("Invalid SOA retry")?,
+                expire: expire.parse().context("Invalid SOA expire")?,
+                minimum: minimum.parse().context("Invalid SOA minimum")?,
+            }
+        }
+        RType::CAA => parse_caa(rdata)?,
+        RType::SVCB => RData::SVCB(rdata.parse().context("Invalid SVCB record")?),
+        RType::HTTPS => RData::HTTPS(rdata.parse().context("Invalid HTTPS record")?),
+        _ => return Err(anyhow!("{} records need the generic '\\# length hex' RDATA format", rtype)),
     };
     Ok(rdata)
 }
 
-#[derive(Debug, PartialEq)]
+/// Parses the hex part of generic RDATA (`\# 4 0A000001`), which may be split up by whitespace
+fn parse_generic_rdata(generic: &str) -> anyhow::Result<Vec<u8>> {
+    let mut fields = generic.split_whitespace();
+    let length: usize = fields
+        .next()
+        .context("Missing length in generic RDATA")?
+        .parse()
+        .context("Invalid length in generic RDATA")?;
+    let hex: String = fields.collect();
+    let bytes = from_hex(&hex).context("Invalid hex in generic RDATA")?;
+    if bytes.len() != length {
+        return Err(anyhow!("Generic RDATA is {} bytes, but its length says {}", bytes.len(), length));
+    }
+    Ok(bytes)
+}
+
+/// Parses RDATA like `0 issue "letsencrypt.org"`. The value may be quoted, contain spaces and use `\"`, `\\` and
+/// `\DDD` escapes.
+fn parse_caa(rdata: &str) -> anyhow::Result<RData> {
+    let mut fields = rdata.split_whitespace();
+    let (Some(flags), Some(tag), Some(value)) = (fields.next(), fields.next(), fields.remainder()) else {
+        return Err(anyhow!("Expected flags, tag and value in CAA record '{}'", rdata));
+    };
+    let flags: u8 = flags.parse().context("Invalid CAA flags")?;
+    let value = value.trim_end();
+    let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+    let mut bytes = Vec::new();
+    let mut chars = value.bytes();
+    while let Some(c) = chars.next() {
+        bytes.push(if c == b'\\' { unescape(&mut chars)? } else { c });
+    }
+    let value = String::from_utf8_lossy(&bytes);
+
+    if !is_caa_tag(tag.as_bytes()) {
+        return Err(anyhow!("Invalid CAA tag '{}'", tag));
+    }
+    let tag = tag.to_ascii_lowercase();
+    match tag.as_str() {
+        // An issuer domain followed by optional parameters, or just ";" to forbid issuance
+        "issue" | "issuewild" => {
+            let issuer = value.split(';').next().unwrap_or_default().trim();
+            let is_label = |l: &str| !l.is_empty() && l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+            if !issuer.is_empty() && !issuer.split('.').all(is_label) {
+                return Err(anyhow!("Invalid CAA issuer domain '{}'", issuer));
+            }
+        }
+        "iodef" if !["mailto:", "http://", "https://"].iter().any(|scheme| value.starts_with(scheme)) => {
+            return Err(anyhow!("CAA iodef must be a mailto: or http(s) URL, got '{}'", value));
+        }
+        _ => {}
+    }
+
+    Ok(RData::CAA { flags, tag, value: bytes })
+}
+
+/// Splits RDATA like "10 mail.example.com" into exactly `N` whitespace separated fields
+fn rdata_fields<const N: usize>(rdata: &str) -> anyhow::Result<[&str; N]> {
+    let fields: Vec<&str> = rdata.split_whitespace().collect();
+    fields
+        .try_into()
+        .map_err(|fields: Vec<&str>| anyhow!("Expected {} RDATA fields, got {} in '{}'", N, fields.len(), rdata))
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct VecDNSRecord(Vec<DNSRecord>);
 
 impl From<Vec<DNSRecord>> for VecDNSRecord {
@@ -108,24 +281,95 @@ impl Deref for VecDNSRecord {
     }
 }
 
-impl DekuWrite<(CompressedRef, u16)> for VecDNSRecord {
-    fn write(&self, output: &mut BitVec<u8, Msb0>, ctx: (CompressedRef, u16)) -> Result<(), DekuError> {
-        for record in self.0.iter().take(ctx.1 as usize) {
-            record.write(output, ctx.0.clone())?;
-        }
-        Ok(())
+impl DerefMut for VecDNSRecord {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl VecDNSRecord {
+    pub(crate) fn read(reader: &mut Reader<'_>, count: u16) -> Result<Self, WireError> {
+        let records = (0..count).map(|_| DNSRecord::read(reader)).collect::<Result<_, _>>()?;
+        Ok(VecDNSRecord(records))
+    }
+
+    pub(crate) fn write(&self, writer: &mut Writer<'_>) -> Result<(), WireError> {
+        self.0.iter().try_for_each(|record| record.write(writer))
     }
 }
 
-impl<'a> DekuRead<'a, (CompressedRef, u16)> for VecDNSRecord {
-    fn read(input: &'a BitSlice<u8, Msb0>, ctx: (CompressedRef, u16)) -> Result<(&'a BitSlice<u8, Msb0>, Self), DekuError> {
-        let mut records = Vec::new();
-        let mut input = input;
-        for _ in 0..ctx.1 {
-            let (i, record) = DNSRecord::read(input, ctx.0.clone())?;
-            input = i;
-            records.push(record);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_caa() {
+        let record = DNSRecord::try_from("example.com CAA 0 issue \"letsencrypt.org; validationmethods=dns-01\"").unwrap();
+        assert_eq!(
+            record.rdata,
+            RData::CAA {
+                flags: 0,
+                tag: "issue".to_string(),
+                value: b"letsencrypt.org; validationmethods=dns-01".to_vec(),
+            }
+        );
+
+        assert!(DNSRecord::try_from("example.com CAA 0 issuewild \";\"").is_ok());
+        assert!(DNSRecord::try_from("example.com CAA 128 iodef mailto:security@example.com").is_ok());
+        assert!(DNSRecord::try_from("example.com CAA 0 iodef security@example.com").is_err());
+        assert!(DNSRecord::try_from("example.com CAA 0 issue bad_ca.org").is_err());
+        assert!(DNSRecord::try_from("example.com CAA 0 is-sue letsencrypt.org").is_err());
+        assert!(DNSRecord::try_from("example.com CAA 256 issue letsencrypt.org").is_err());
+
+        // Fields can be separated by any run of whitespace, and the value can have escapes
+        let record = DNSRecord::try_from("example.com CAA 0  issue\t\"ca.example.net; note=\\\"x\\\" \\255\"").unwrap();
+        let RData::CAA { tag, value, .. } = &record.rdata else {
+            panic!("{:?}", record.rdata)
+        };
+        assert_eq!((tag.as_str(), value.as_slice()), ("issue", b"ca.example.net; note=\"x\" \xFF".as_slice()));
+        assert_eq!(record.rdata.to_string(), r#"0 issue "ca.example.net; note=\"x\" \255""#);
+        assert_eq!(DNSRecord::try_from(&*record.to_string().replace('\t', " ")).unwrap().rdata, record.rdata);
+    }
+
+    #[test]
+    fn test_generic_rdata() {
+        let record = DNSRecord::try_from("example.com TYPE65280 \\# 3 0102 03").unwrap();
+        assert_eq!(record.rtype, RType::Unknown(65280));
+        assert_eq!(record.rdata, RData::Opaque(vec![1, 2, 3]));
+
+        // Known types are decoded
+        let record = DNSRecord::try_from("example.com TYPE1 \\# 4 0A000001").unwrap();
+        assert_eq!(record, DNSRecord::try_from("example.com A 10.0.0.1").unwrap());
+
+        assert!(DNSRecord::try_from("example.com TYPE65280 \\# 0").is_ok());
+        assert!(DNSRecord::try_from("example.com TYPE65280 \\# 2 010203").is_err());
+        assert!(DNSRecord::try_from("example.com TYPE65280 \\# 1 0").is_err());
+        assert!(DNSRecord::try_from("example.com TYPE1 \\# 3 0A0000").is_err());
+        assert!(DNSRecord::try_from("example.com TYPE65280 0102").is_err());
+    }
+
+    #[test]
+    fn test_display_roundtrip() {
+        let record = DNSRecord::try_from("example.com A 10.0.0.1").unwrap();
+        assert_eq!(record.to_string(), "example.com.\t60\tIN\tA\t10.0.0.1");
+
+        for text in [
+            "example.com 300 IN AAAA 2001:db8::1",
+            "example.com IN 300 CNAME www.example.org",
+            "example.com CH TXT \"hello world\" \"a\\\"b\"",
+            "_sip._tcp.example.com SRV 10 5 5060 sip.example.com",
+            "example.com MX 10 .",
+            "example.com SOA ns1.example.com hostmaster.example.com 1 7200 3600 1209600 30",
+            "example.com CAA 0 issue \"letsencrypt.org; validationmethods=dns-01\"",
+            "example.com HTTPS 1 . alpn=h3,h2 port=443",
+            "a\\.b.example.com CLASS42 PTR example.com",
+            "example.com TYPE65280 \\# 3 010203",
+            "example.com TYPE65280 \\# 0",
+        ] {
+            let record = DNSRecord::try_from(text).unwrap();
+            let display = record.to_string();
+            assert_eq!(DNSRecord::try_from(display.as_str()).unwrap(), record, "{}", display);
         }
-        Ok((input, VecDNSRecord(records)))
+        assert_eq!(DNSRecord::try_from("example.com 300 CH A 10.0.0.1").unwrap().class, 3);
     }
 }
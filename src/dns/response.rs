@@ -1,43 +1,230 @@
-use deku::prelude::*;
+use std::fmt::{Display, Formatter};
 
 use crate::{
     dns::{
-        compression::CompressedRef,
+        compression,
+        cookie::Cookie,
+        ecs::{ClientSubnet, OPTION_ECS},
+        ede::{ExtendedError, OPTION_EDE},
+        edns::{Edns, EdnsOption, ExtendedRcode, OPTION_COOKIE, SERVER_UDP_PAYLOAD},
         header,
         header::{DNSHeader, Rcode},
-        question::DNSQuestion,
+        padding::{PaddingPolicy, OPTION_PADDING},
+        question::{fmt_message, DNSQuestion, Question},
         record::{DNSRecord, VecDNSRecord},
+        rtypes::RType,
+        tsig::ResponseTsig,
+        wire::{WireError, Writer},
     },
     nameserver::records::{Records, ResponseSection},
 };
 
-#[derive(Debug, PartialEq, DekuWrite)]
-#[deku(ctx = "is_tcp: bool")]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Response {
-    #[deku(skip)]
-    compress: CompressedRef,
-    #[deku(ctx = "is_tcp")]
-    pub(crate) header: DNSHeader,
-    #[deku(ctx = "Clone::clone(compress)")]
-    question: DNSQuestion,
-
-    #[deku(ctx = "Clone::clone(compress), header.ancount")]
+    compress: bool,
+    tcp: bool,
+    header: DNSHeader,
+    /// Usually the one question from the query, but empty when answering a query we couldn't parse or one
+    /// without a question
+    questions: Vec<DNSQuestion>,
+
     answer: VecDNSRecord,
-    #[deku(ctx = "Clone::clone(compress), header.nscount")]
     authority: VecDNSRecord,
-    #[deku(ctx = "Clone::clone(compress), header.arcount")]
     additional: VecDNSRecord,
+    edns: Option<Edns>,
+
+    /// Largest UDP message the client can take
+    max_udp_len: u16,
+    /// Sent as EDE options, but only to clients that sent an OPT record
+    extended_errors: Vec<ExtendedError>,
+    cookie: Option<Cookie>,
+    client_subnet: Option<ClientSubnet>,
+    padding: PaddingPolicy,
+    tsig: Option<ResponseTsig>,
 }
 
 impl Response {
+    pub fn header(&self) -> &DNSHeader {
+        &self.header
+    }
+
+    /// Section counts are overwritten from the sections when serializing
+    pub fn header_mut(&mut self) -> &mut DNSHeader {
+        &mut self.header
+    }
+
     pub fn set_return_code(&mut self, error: Rcode) {
-        self.header.rcode = error;
+        self.header.set_rcode(error);
+    }
+
+    /// Name compression is on by default. Turning it off writes every name in full, which is handy when debugging.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compress = enabled;
+    }
+
+    /// Adds our OPT record in reply to a client that sent one, and lets UDP responses grow up to the
+    /// client's advertised payload size (capped by ours).
+    pub fn set_edns(&mut self, client: &Edns) {
+        let mut edns = Edns::new(SERVER_UDP_PAYLOAD);
+        edns.dnssec_ok = client.dnssec_ok;
+        self.edns = Some(edns);
+        self.max_udp_len = client.udp_payload_size.clamp(header::TRUNCATE_BYTES, SERVER_UDP_PAYLOAD);
+    }
+
+    /// Sets an RCODE that doesn't fit in the header. Requires `set_edns` to have been called first.
+    pub fn set_extended_return_code(&mut self, rcode: ExtendedRcode) {
+        let (header_rcode, extended_rcode) = rcode.split();
+        self.header.set_rcode(header_rcode);
+        if let Some(edns) = self.edns.as_mut() {
+            edns.extended_rcode = extended_rcode;
+        }
+    }
+
+    /// Tells the client why the query failed (RFC 8914). Can be called before or after `set_edns`.
+    pub fn add_extended_error(&mut self, error: ExtendedError) {
+        self.extended_errors.push(error);
+    }
+
+    /// Answers a client that sent a cookie with ours (RFC 7873 5.2). Only sent alongside an OPT record.
+    pub fn set_cookie(&mut self, cookie: Cookie) {
+        self.cookie = Some(cookie);
+    }
+
+    /// Echoes the query's client subnet with the scope the answer is valid for (RFC 7871 7.2.1). Only sent
+    /// alongside an OPT record.
+    pub fn set_client_subnet(&mut self, subnet: ClientSubnet) {
+        self.client_subnet = Some(subnet);
+    }
+
+    pub fn client_subnet(&self) -> Option<&ClientSubnet> {
+        self.client_subnet.as_ref()
+    }
+
+    /// Pads the OPT record so the message length gives less away. Only meant for encrypted transports, and only
+    /// applies to clients that sent an OPT record.
+    pub fn set_padding(&mut self, padding: PaddingPolicy) {
+        self.padding = padding;
+    }
+
+    /// Signs the serialized message with TSIG (RFC 8945), in reply to a signed query
+    pub fn set_tsig(&mut self, tsig: ResponseTsig) {
+        self.tsig = Some(tsig);
+    }
+
+    /// Lowers the largest UDP message we'll send, e.g. to clients we couldn't verify
+    pub fn limit_udp_len(&mut self, len: u16) {
+        self.max_udp_len = self.max_udp_len.min(len);
+    }
+
+    /// Our OPT record, with the options set on the response
+    fn edns_with_options(&self) -> Option<Edns> {
+        let mut edns = self.edns.clone()?;
+        edns.options
+            .retain(|option| ![OPTION_EDE, OPTION_COOKIE, OPTION_ECS, OPTION_PADDING].contains(&option.code));
+        edns.options.extend(self.client_subnet.iter().map(EdnsOption::from));
+        edns.options.extend(self.cookie.iter().map(EdnsOption::from));
+        edns.options.extend(self.extended_errors.iter().map(EdnsOption::from));
+        Some(edns)
+    }
+
+    fn update_options(&mut self) {
+        self.edns = self.edns_with_options();
     }
-    pub(crate) fn clear(&self) {
-        self.compress.clear();
+
+    /// The header, with counts matching the sections
+    fn counted_header(&self) -> DNSHeader {
+        let mut header = self.header.clone();
+        header.set_counts(
+            self.questions.len() as u16,
+            self.answer.len() as u16,
+            self.authority.len() as u16,
+            (self.additional.len() + usize::from(self.edns.is_some())) as u16,
+        );
+        header
+    }
+
+    fn update_counts(&mut self) {
+        self.header = self.counted_header();
+    }
+
+    /// Serializes the message. A UDP response that doesn't fit is cut back to the question (and OPT record, if
+    /// any) with TC set. The TSIG record, if any, is appended last, so room is kept for it.
+    pub fn serialize(&mut self) -> Result<Vec<u8>, WireError> {
+        let mut output = Vec::new();
+        self.serialize_into(&mut output)?;
+        Ok(output)
+    }
+
+    /// Like `serialize`, but appends to `output` so one buffer can be reused for every message. `output` is left
+    /// as it was if the message can't be written.
+    pub fn serialize_into(&mut self, output: &mut Vec<u8>) -> Result<(), WireError> {
+        let start = output.len();
+        let result = self.write(&mut Writer::new(output).with_compression(self.compress));
+        if result.is_err() {
+            output.truncate(start);
+        }
+        result
     }
 
-    pub(crate) fn build_from_record_iter(id: u16, question: DNSQuestion, records: &Records, tcp: bool) -> Response {
+    fn write(&mut self, writer: &mut Writer<'_>) -> Result<(), WireError> {
+        let tsig_len = self.tsig.as_ref().map_or(0, ResponseTsig::record_len);
+        self.update_options();
+        self.update_counts();
+
+        self.header.write(writer);
+        for question in &self.questions {
+            question.write(writer)?;
+        }
+        let questions_end = writer.position();
+        for section in [&self.answer, &self.authority, &self.additional] {
+            section.write(writer)?;
+        }
+        let mut edns_start = writer.position();
+        if let Some(edns) = &self.edns {
+            edns.write(writer)?;
+        }
+
+        if self
+            .header
+            .update_from_total_msg_len(writer.position() + tsig_len, self.max_udp_len as usize, self.tcp)
+        {
+            self.answer.clear();
+            self.authority.clear();
+            self.additional.clear();
+            self.update_counts();
+
+            writer.truncate(questions_end);
+            writer.patch(0, &self.header.to_bytes());
+            edns_start = writer.position();
+            if let Some(edns) = &self.edns {
+                edns.write(writer)?;
+            }
+        }
+
+        // The padding option goes last, so only the OPT record needs rewriting
+        let max_len = if self.tcp { u16::MAX } else { self.max_udp_len };
+        if let (Some(edns), Some(len)) = (
+            self.edns.as_mut(),
+            self.padding.padding_len(writer.position() + tsig_len, max_len as usize),
+        ) {
+            edns.options.push(EdnsOption::new(OPTION_PADDING, vec![0; len]));
+            writer.truncate(edns_start);
+            edns.write(writer)?;
+        }
+
+        if let Some(tsig) = &self.tsig {
+            tsig.sign(writer)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn build_from_record_iter(
+        id: u16,
+        question: DNSQuestion,
+        records: &Records,
+        _client_subnet: Option<&ClientSubnet>,
+        tcp: bool,
+    ) -> Response {
         let mut answer = Vec::new();
         let mut authority = Vec::new();
         let mut additional = Vec::new();
@@ -50,13 +237,59 @@ impl Response {
             }
         }
 
-        for record in &authority {
-            if let Some(dnsname) = record.rdata.try_get_name() {
-                additional.extend(records.additional_section(dnsname).map(|r| r.record.clone()));
+        // NS referrals, MX exchanges and SRV/SVCB targets get their in-zone addresses added
+        for record in answer.iter().chain(&authority) {
+            if let Some(dnsname) = record.rdata.additional_name() {
+                for glue in records.additional_section(dnsname) {
+                    if !additional.contains(glue.record) {
+                        additional.push(glue.record.clone());
+                    }
+                }
             }
         }
 
-        Response::new(id, question, answer, authority, additional, tcp, Rcode::NoError)
+        let is_referral = authority.iter().any(|r| r.rtype == RType::NS);
+        let zone_soa = records.zone_soa(&question.qname);
+        let mut rcode = Rcode::NoError;
+        if answer.is_empty() && !is_referral {
+            if let Some(soa) = zone_soa {
+                if !records.name_exists(&question.qname) {
+                    rcode = Rcode::NxDomain;
+                }
+                authority.push(soa.to_negative_soa());
+            }
+        }
+
+        // Referrals point at the zone's real authority; anything else we only answer for our own zones
+        let authoritative = !is_referral && (zone_soa.is_some() || !answer.is_empty());
+        let mut response = Response::new(id, question, answer, authority, additional, tcp, rcode);
+        response.header.set_authoritative(authoritative);
+        response
+    }
+
+    /// FORMERR for a query that couldn't be parsed. Only the ID is echoed back (RFC 1035 section 4.1.1).
+    pub fn format_error(id: u16, tcp: bool) -> Response {
+        Response::from_questions(id, Vec::new(), Rcode::FormatError, tcp)
+    }
+
+    /// Empty response echoing any number of questions
+    pub fn from_questions(id: u16, questions: Vec<DNSQuestion>, rcode: Rcode, tcp: bool) -> Response {
+        Response {
+            compress: compression::enabled_by_default(),
+            tcp,
+            header: header::response_header(id, questions.len(), 0, 0, 0, false, rcode),
+            questions,
+            answer: Vec::new().into(),
+            authority: Vec::new().into(),
+            additional: Vec::new().into(),
+            edns: None,
+            max_udp_len: header::TRUNCATE_BYTES,
+            extended_errors: Vec::new(),
+            cookie: None,
+            client_subnet: None,
+            padding: PaddingPolicy::None,
+            tsig: None,
+        }
     }
 
     pub fn from_rcode(id: u16, question: DNSQuestion, rcode: Rcode, tcp: bool) -> Response {
@@ -73,12 +306,248 @@ impl Response {
         rcode: Rcode,
     ) -> Response {
         Response {
-            compress: CompressedRef::new(tcp),
-            header: header::response_header(id, answer.len(), authority.len(), additional.len(), tcp, false, rcode),
-            question,
+            compress: compression::enabled_by_default(),
+            tcp,
+            header: header::response_header(id, 1, answer.len(), authority.len(), additional.len(), false, rcode),
+            questions: vec![question],
+            answer: answer.into(),
+            authority: authority.into(),
+            additional: additional.into(),
+            edns: None,
+            max_udp_len: header::TRUNCATE_BYTES,
+            extended_errors: Vec::new(),
+            cookie: None,
+            client_subnet: None,
+            padding: PaddingPolicy::None,
+            tsig: None,
+        }
+    }
+
+    /// A message written exactly as given, e.g. one decoded from elsewhere. The header is only changed to count the
+    /// sections, OPT and TSIG records stay as they are in `additional`, and nothing is ever truncated.
+    pub(crate) fn from_parts(
+        header: DNSHeader,
+        questions: Vec<DNSQuestion>,
+        answer: Vec<DNSRecord>,
+        authority: Vec<DNSRecord>,
+        additional: Vec<DNSRecord>,
+    ) -> Response {
+        Response {
+            compress: compression::enabled_by_default(),
+            tcp: false,
+            header,
+            questions,
             answer: answer.into(),
             authority: authority.into(),
             additional: additional.into(),
+            edns: None,
+            max_udp_len: u16::MAX,
+            extended_errors: Vec::new(),
+            cookie: None,
+            client_subnet: None,
+            padding: PaddingPolicy::None,
+            tsig: None,
+        }
+    }
+}
+
+impl From<Question> for Response {
+    /// Writes a decoded message back out as it came, like `from_parts`
+    fn from(message: Question) -> Self {
+        Response::from_parts(
+            message.header().clone(),
+            message.questions,
+            message.answer.to_vec(),
+            message.authority.to_vec(),
+            message.additional.to_vec(),
+        )
+    }
+}
+
+impl Display for Response {
+    /// The message as it would be sent, minus the TSIG record, which is only made when serializing
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        fmt_message(
+            f,
+            &self.counted_header(),
+            self.edns_with_options().as_ref(),
+            &self.questions,
+            [&self.answer, &self.authority, &self.additional],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{ede::EdeCode, name::DNSName};
+
+    fn referral() -> Response {
+        let question = DNSQuestion::new(DNSName::from_url("www.example.com"), RType::A);
+        let answer = DNSRecord::try_from("www.example.com A 10.0.0.1").unwrap();
+        let authority = DNSRecord::try_from("example.com NS ns1.example.com").unwrap();
+        Response::new(1, question, vec![answer], vec![authority], vec![], false, Rcode::NoError)
+    }
+
+    #[test]
+    fn test_compression_pointers() {
+        let bytes = referral().serialize().unwrap();
+
+        // Question name starts right after the 12 byte header, so the answer owner points to 12
+        assert_eq!(bytes[33..35], [0xC0, 0x0C]);
+        // "example.com" is a suffix of the question name, starting at 16
+        assert_eq!(bytes[49..51], [0xC0, 0x10]);
+        // NS RDATA is compressible: "ns1" followed by a pointer, with RDLENGTH covering only what was written
+        assert_eq!(bytes[59..61], [0, 6]);
+        assert_eq!(bytes[61..], [3, b'n', b's', b'1', 0xC0, 0x10]);
+    }
+
+    #[test]
+    fn test_mx_srv_additional() {
+        let records: Records = [
+            "example.com MX 10 mail.example.com",
+            "_sip._tcp.example.com SRV 1 5 5060 sip.example.com",
+            "mail.example.com A 10.0.0.2",
+            "mail.example.com AAAA 2001:db8::2",
+            "sip.example.com A 10.0.0.3",
+            "other.example.com A 10.0.0.4",
+        ]
+        .into_iter()
+        .map(|r| DNSRecord::try_from(r).unwrap())
+        .collect();
+
+        let question = DNSQuestion::new(DNSName::from_url("example.com"), RType::MX);
+        let response = Response::build_from_record_iter(1, question, &records, None, false);
+        let additional: Vec<_> = response.additional.iter().map(|r| (r.name.to_string(), r.rtype)).collect();
+        assert_eq!(
+            additional,
+            [("mail.example.com".to_string(), RType::A), ("mail.example.com".to_string(), RType::AAAA)]
+        );
+
+        let question = DNSQuestion::new(DNSName::from_url("_sip._tcp.example.com"), RType::SRV);
+        let response = Response::build_from_record_iter(1, question, &records, None, false);
+        let additional: Vec<_> = response.additional.iter().map(|r| (r.name.to_string(), r.rtype)).collect();
+        assert_eq!(additional, [("sip.example.com".to_string(), RType::A)]);
+    }
+
+    #[test]
+    fn test_https_additional() {
+        let records: Records = ["example.com HTTPS 1 svc.example.com alpn=h3,h2", "svc.example.com AAAA 2001:db8::3"]
+            .into_iter()
+            .map(|r| DNSRecord::try_from(r).unwrap())
+            .collect();
+
+        let question = DNSQuestion::new(DNSName::from_url("example.com"), RType::HTTPS);
+        let mut response = Response::build_from_record_iter(1, question, &records, None, false);
+        assert_eq!(response.answer.len(), 1);
+        assert_eq!(response.additional[0].rtype, RType::AAAA);
+
+        // TargetName isn't compressed even though "example.com" was already written
+        let bytes = response.serialize().unwrap();
+        let target = b"\x03svc\x07example\x03com\x00";
+        assert!(bytes.windows(target.len()).any(|w| w == target));
+    }
+
+    fn zone() -> Records {
+        [
+            "example.com SOA ns1.example.com hostmaster.example.com 1 7200 3600 1209600 30",
+            "example.com NS ns1.example.com",
+            "ns1.example.com A 10.0.0.1",
+            "a.b.example.com A 10.0.0.2",
+        ]
+        .into_iter()
+        .map(|r| DNSRecord::try_from(r).unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn test_nodata() {
+        for name in ["ns1.example.com", "b.example.com"] {
+            let question = DNSQuestion::new(DNSName::from_url(name), RType::AAAA);
+            let response = Response::build_from_record_iter(1, question, &zone(), None, false);
+
+            assert_eq!(response.header().rcode(), Rcode::NoError);
+            assert!(response.answer.is_empty());
+            assert_eq!(response.authority.len(), 1);
+            assert_eq!(response.authority[0].rtype, RType::SOA);
+            // TTL 60 capped at SOA MINIMUM
+            assert_eq!(response.authority[0].ttl, 30);
         }
     }
+
+    #[test]
+    fn test_nxdomain() {
+        let question = DNSQuestion::new(DNSName::from_url("missing.example.com"), RType::A);
+        let response = Response::build_from_record_iter(1, question, &zone(), None, false);
+
+        assert_eq!(response.header().rcode(), Rcode::NxDomain);
+        assert_eq!(response.authority.len(), 1);
+        assert_eq!(response.authority[0].rtype, RType::SOA);
+    }
+
+    #[test]
+    fn test_apex_ns_is_not_referral() {
+        let question = DNSQuestion::new(DNSName::from_url("example.com"), RType::NS);
+        let response = Response::build_from_record_iter(1, question, &zone(), None, false);
+
+        assert_eq!(response.answer.len(), 1);
+        assert!(response.authority.is_empty());
+        assert_eq!(response.additional.iter().filter(|r| r.rtype == RType::A).count(), 1);
+    }
+
+    #[test]
+    fn test_compression_disabled() {
+        let mut response = referral();
+        response.set_compression(false);
+        let bytes = response.serialize().unwrap();
+
+        assert_eq!(bytes[33..50], *b"\x03www\x07example\x03com\x00");
+        assert_eq!(bytes.len(), 104);
+    }
+
+    #[test]
+    fn test_uncompressible_rdata() {
+        let question = DNSQuestion::new(DNSName::from_url("example.com"), RType::TXT);
+        let answer = DNSRecord::try_from("example.com TXT example.com").unwrap();
+        let mut response = Response::new(1, question, vec![answer], vec![], vec![], false, Rcode::NoError);
+        let bytes = response.serialize().unwrap();
+
+        // TXT RDATA is never compressed even though it matches the question name
+        assert_eq!(bytes[bytes.len() - 11..], *b"example.com");
+    }
+
+    #[test]
+    fn test_display() {
+        let mut response = referral();
+        response.header_mut().set_recursion_desired(true);
+        let mut edns = Edns::new(4096);
+        edns.dnssec_ok = true;
+        response.set_edns(&edns);
+        response.set_extended_return_code(ExtendedRcode::BadVers);
+        response.add_extended_error(ExtendedError::new(EdeCode::Other, "test"));
+
+        let expected = "\
+;; ->>HEADER<<- opcode: QUERY, status: BADVERS, id: 1
+;; flags: qr rd; QUERY: 1, ANSWER: 1, AUTHORITY: 1, ADDITIONAL: 1
+
+;; OPT PSEUDOSECTION:
+; EDNS: version: 0, flags: do; udp: 1232
+; EDE: 0 (Other): (test)
+
+;; QUESTION SECTION:
+;www.example.com.\t\tIN\tA
+
+;; ANSWER SECTION:
+www.example.com.\t60\tIN\tA\t10.0.0.1
+
+;; AUTHORITY SECTION:
+example.com.\t60\tIN\tNS\tns1.example.com.
+";
+        assert_eq!(response.to_string(), expected);
+
+        // A query parsed back from the wire prints the same
+        let bytes = response.serialize().unwrap();
+        let parsed = Question::parse(&bytes).unwrap();
+        assert_eq!(parsed.to_string(), expected);
+    }
 }
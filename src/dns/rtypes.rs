@@ -1,23 +1,8 @@
-use deku::{
-    bitvec::{BitSlice, BitVec, Msb0},
-    ctx::Endian,
-    prelude::*,
+use anyhow::anyhow;
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
 };
-use std::str::FromStr;
-
-pub enum ContainsIP {
-    Yes,
-    No,
-}
-
-impl ContainsIP {
-    pub fn from_class(c: RType) -> Self {
-        match c {
-            RType::A => ContainsIP::Yes,
-            _ => ContainsIP::No,
-        }
-    }
-}
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[allow(clippy::upper_case_acronyms)]
@@ -26,41 +11,38 @@ pub enum RType {
     CNAME = 5,
     A = 1,
     NS = 2,
+    SOA = 6,
+    PTR = 12,
+    MX = 15,
+    SRV = 33,
     OPT = 41,
     AAAA = 28,
     TXT = 16,
+    SVCB = 64,
     HTTPS = 65,
     CAA = 257,
     DS = 43,
+    TSIG = 250,
     Unknown(u16),
 }
 
-impl DekuWrite<Endian> for RType {
-    fn write(&self, output: &mut BitVec<u8, Msb0>, ctx: Endian) -> Result<(), DekuError> {
-        let field = to_int(*self);
-        field.write(output, ctx)
-    }
-}
-
-impl DekuRead<'_, Endian> for RType {
-    fn read(input: &BitSlice<u8, Msb0>, ctx: Endian) -> Result<(&BitSlice<u8, Msb0>, Self), DekuError> {
-        let (rest, field) = u16::read(input, ctx)?;
-        let rtype = from_int(field);
-        Ok((rest, rtype))
-    }
-}
-
 fn from_int(field: u16) -> RType {
     match field {
         5 => RType::CNAME,
         1 => RType::A,
         2 => RType::NS,
+        6 => RType::SOA,
+        12 => RType::PTR,
+        15 => RType::MX,
+        33 => RType::SRV,
         41 => RType::OPT,
         28 => RType::AAAA,
         16 => RType::TXT,
+        64 => RType::SVCB,
         65 => RType::HTTPS,
         257 => RType::CAA,
         43 => RType::DS,
+        250 => RType::TSIG,
         _ => RType::Unknown(field),
     }
 }
@@ -70,43 +52,115 @@ fn to_int(rtype: RType) -> u16 {
         RType::CNAME => 5,
         RType::A => 1,
         RType::NS => 2,
+        RType::SOA => 6,
+        RType::PTR => 12,
+        RType::MX => 15,
+        RType::SRV => 33,
         RType::OPT => 41,
         RType::AAAA => 28,
         RType::TXT => 16,
+        RType::SVCB => 64,
         RType::HTTPS => 65,
         RType::CAA => 257,
         RType::DS => 43,
+        RType::TSIG => 250,
         RType::Unknown(x) => x,
     }
 }
 
+impl From<u16> for RType {
+    fn from(value: u16) -> Self {
+        from_int(value)
+    }
+}
+
+impl From<RType> for u16 {
+    fn from(rtype: RType) -> Self {
+        to_int(rtype)
+    }
+}
+
 impl RType {
+    /// Whether names inside the RDATA of this type may be compressed.
+    /// RFC 3597 section 4 limits this to the well-known types from RFC 1035 (CNAME, NS, SOA, MX, PTR, ...).
     pub(crate) fn supports_compression(&self) -> bool {
         match self {
             RType::CNAME => true,
             RType::NS => true,
+            RType::SOA => true,
+            RType::PTR => true,
+            RType::MX => true,
+            RType::SRV => false,
             RType::A => false,
             RType::OPT => false,
             RType::AAAA => false,
             RType::TXT => false,
+            RType::SVCB => false,
             RType::HTTPS => false,
             _ => false,
         }
     }
 }
 
+impl RType {
+    /// QTYPEs that ask for something other than records of one type (OPT, and AXFR, ANY, ... in 128-255).
+    /// We don't have anything to answer these with, unlike types we just have no data for.
+    pub(crate) fn is_meta(&self) -> bool {
+        matches!(to_int(*self), 41 | 128..=255)
+    }
+}
+
+impl Display for RType {
+    /// The mnemonic, or `TYPEnnn` for types without one (RFC 3597 section 5)
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RType::Unknown(value) => write!(f, "TYPE{}", value),
+            known => write!(f, "{:?}", known),
+        }
+    }
+}
+
 impl FromStr for RType {
-    type Err = DekuError;
+    type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
+        if let Some(value) = s.to_ascii_uppercase().strip_prefix("TYPE").and_then(|n| n.parse().ok()) {
+            return Ok(from_int(value));
+        }
+        match s.to_ascii_uppercase().as_str() {
             "CNAME" => Ok(RType::CNAME),
             "A" => Ok(RType::A),
             "NS" => Ok(RType::NS),
             "OPT" => Ok(RType::OPT),
             "AAAA" => Ok(RType::AAAA),
             "TXT" => Ok(RType::TXT),
-            _ => Err(DekuError::Parse(format!("Invalid record type: {}", s.to_string()))),
+            "SOA" => Ok(RType::SOA),
+            "PTR" => Ok(RType::PTR),
+            "MX" => Ok(RType::MX),
+            "SRV" => Ok(RType::SRV),
+            "SVCB" => Ok(RType::SVCB),
+            "HTTPS" => Ok(RType::HTTPS),
+            "CAA" => Ok(RType::CAA),
+            "DS" => Ok(RType::DS),
+            "TSIG" => Ok(RType::TSIG),
+            _ => Err(anyhow!("Invalid record type: {}", s)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mnemonics() {
+        assert_eq!(RType::from_str("mx").unwrap(), RType::MX);
+        assert_eq!(RType::from_str("TYPE15").unwrap(), RType::MX);
+        assert_eq!(RType::from_str("type65280").unwrap(), RType::Unknown(65280));
+        assert!(RType::from_str("TYPE65536").is_err());
+        assert!(RType::from_str("BOGUS").is_err());
+
+        assert_eq!(RType::Unknown(65280).to_string(), "TYPE65280");
+        assert_eq!(RType::AAAA.to_string(), "AAAA");
+    }
+}
@@ -0,0 +1,503 @@
+// SVCB and HTTPS records (RFC 9460)
+
+use crate::dns::{
+    name::DNSName,
+    text::{escape, unescape},
+    wire::{Reader, WireError, Writer},
+};
+use anyhow::{anyhow, Context};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::{
+    fmt::{Display, Formatter},
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+const MANDATORY: u16 = 0;
+const ALPN: u16 = 1;
+const NO_DEFAULT_ALPN: u16 = 2;
+const PORT: u16 = 3;
+const IPV4HINT: u16 = 4;
+const ECH: u16 = 5;
+const IPV6HINT: u16 = 6;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SvcParam {
+    Mandatory(Vec<u16>),
+    /// Protocol ids, which are arbitrary bytes
+    Alpn(Vec<Vec<u8>>),
+    NoDefaultAlpn,
+    Port(u16),
+    Ipv4Hint(Vec<Ipv4Addr>),
+    /// ECHConfigList, base64 in the text format
+    Ech(Vec<u8>),
+    Ipv6Hint(Vec<Ipv6Addr>),
+    /// Any other key, written as keyNNNNN in the text format
+    Unknown(u16, Vec<u8>),
+}
+
+fn key_name(key: u16) -> String {
+    match key {
+        MANDATORY => "mandatory".to_string(),
+        ALPN => "alpn".to_string(),
+        NO_DEFAULT_ALPN => "no-default-alpn".to_string(),
+        PORT => "port".to_string(),
+        IPV4HINT => "ipv4hint".to_string(),
+        ECH => "ech".to_string(),
+        IPV6HINT => "ipv6hint".to_string(),
+        _ => format!("key{}", key),
+    }
+}
+
+fn key_from_name(name: &str) -> anyhow::Result<u16> {
+    Ok(match name {
+        "mandatory" => MANDATORY,
+        "alpn" => ALPN,
+        "no-default-alpn" => NO_DEFAULT_ALPN,
+        "port" => PORT,
+        "ipv4hint" => IPV4HINT,
+        "ech" => ECH,
+        "ipv6hint" => IPV6HINT,
+        _ => name
+            .strip_prefix("key")
+            .and_then(|n| n.parse().ok())
+            .with_context(|| anyhow!("Unknown SvcParamKey '{}'", name))?,
+    })
+}
+
+/// Splits an alpn value into ids. `\,` is a comma inside an id rather than between two, and other escapes are
+/// decoded like in character-strings.
+fn alpn_ids(value: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut ids = vec![Vec::new()];
+    let mut chars = value.bytes();
+    while let Some(c) = chars.next() {
+        match c {
+            b'\\' => ids.last_mut().unwrap().push(unescape(&mut chars)?),
+            b',' => ids.push(Vec::new()),
+            c => ids.last_mut().unwrap().push(c),
+        }
+    }
+    match ids.iter().find(|id| id.is_empty() || id.len() > u8::MAX as usize) {
+        Some(id) => Err(anyhow!("ALPN id of {} bytes in '{}', they have to be 1 to 255", id.len(), value)),
+        None => Ok(ids),
+    }
+}
+
+/// Splits SvcParams at whitespace, apart from whitespace in quoted values. Escapes are left for
+/// `SvcParam::from_text`.
+fn split_params(params: &str) -> anyhow::Result<Vec<&str>> {
+    let bytes = params.as_bytes();
+    let mut split = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i].is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let mut quoted = false;
+        while i < bytes.len() && (quoted || !bytes[i].is_ascii_whitespace()) {
+            match bytes[i] {
+                b'\\' => i += 1,
+                b'"' => quoted = !quoted,
+                _ => {}
+            }
+            i += 1;
+        }
+        if quoted {
+            return Err(anyhow!("Unterminated quoted value in '{}'", &params[start..]));
+        }
+        split.push(&params[start..i.min(bytes.len())]);
+    }
+    Ok(split)
+}
+
+impl SvcParam {
+    pub fn key(&self) -> u16 {
+        match self {
+            SvcParam::Mandatory(_) => MANDATORY,
+            SvcParam::Alpn(_) => ALPN,
+            SvcParam::NoDefaultAlpn => NO_DEFAULT_ALPN,
+            SvcParam::Port(_) => PORT,
+            SvcParam::Ipv4Hint(_) => IPV4HINT,
+            SvcParam::Ech(_) => ECH,
+            SvcParam::Ipv6Hint(_) => IPV6HINT,
+            SvcParam::Unknown(key, _) => *key,
+        }
+    }
+
+    /// Parses `key=value` (or a bare `key`) from the text format. Values may be quoted.
+    fn from_text(param: &str) -> anyhow::Result<Self> {
+        let (key, value) = match param.split_once('=') {
+            Some((key, value)) => (key, Some(value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value))),
+            None => (param, None),
+        };
+        let key = key_from_name(key)?;
+        let value = match (key, value) {
+            (NO_DEFAULT_ALPN, None) => return Ok(SvcParam::NoDefaultAlpn),
+            (NO_DEFAULT_ALPN, Some(_)) => return Err(anyhow!("no-default-alpn doesn't take a value")),
+            (_, Some(value)) => value,
+            (key, None) if key > IPV6HINT => return Ok(SvcParam::Unknown(key, Vec::new())),
+            (key, None) => return Err(anyhow!("SvcParam {} needs a value", key_name(key))),
+        };
+        // Lists can't be empty, and neither can their items, since neither could be read back off the wire
+        let list = || {
+            value.split(',').map(move |item| match item {
+                "" => Err(anyhow!("Empty item in {} list '{}'", key_name(key), value)),
+                item => Ok(item),
+            })
+        };
+
+        Ok(match key {
+            MANDATORY => SvcParam::Mandatory(list().map(|k| key_from_name(k?)).collect::<anyhow::Result<_>>()?),
+            ALPN => SvcParam::Alpn(alpn_ids(value)?),
+            PORT => SvcParam::Port(value.parse().context("Invalid port")?),
+            IPV4HINT => SvcParam::Ipv4Hint(list().map(|ip| Ok(Ipv4Addr::from_str(ip?)?)).collect::<anyhow::Result<_>>()?),
+            ECH => SvcParam::Ech(BASE64.decode(value).context("Invalid ech")?),
+            IPV6HINT => SvcParam::Ipv6Hint(list().map(|ip| Ok(Ipv6Addr::from_str(ip?)?)).collect::<anyhow::Result<_>>()?),
+            key => {
+                let mut bytes = Vec::new();
+                let mut chars = value.bytes();
+                while let Some(c) = chars.next() {
+                    bytes.push(if c == b'\\' { unescape(&mut chars)? } else { c });
+                }
+                SvcParam::Unknown(key, bytes)
+            }
+        })
+    }
+
+    fn value_bytes(&self) -> Result<Vec<u8>, WireError> {
+        Ok(match self {
+            SvcParam::Mandatory(keys) => keys.iter().flat_map(|k| k.to_be_bytes()).collect(),
+            SvcParam::Alpn(ids) => {
+                let mut bytes = Vec::new();
+                for id in ids {
+                    let len = u8::try_from(id.len())
+                        .ok()
+                        .filter(|&len| len > 0)
+                        .ok_or_else(|| WireError::InvalidParam(format!("ALPN id of {} bytes", id.len())))?;
+                    bytes.push(len);
+                    bytes.extend(id);
+                }
+                bytes
+            }
+            SvcParam::NoDefaultAlpn => Vec::new(),
+            SvcParam::Port(port) => port.to_be_bytes().to_vec(),
+            SvcParam::Ipv4Hint(ips) => ips.iter().flat_map(|ip| ip.octets()).collect(),
+            SvcParam::Ech(ech) => ech.clone(),
+            SvcParam::Ipv6Hint(ips) => ips.iter().flat_map(|ip| ip.octets()).collect(),
+            SvcParam::Unknown(_, value) => value.clone(),
+        })
+    }
+
+    fn from_bytes(key: u16, value: Vec<u8>) -> Result<Self, WireError> {
+        let invalid = || WireError::Parse(format!("Invalid value for SvcParam {}", key_name(key)));
+        Ok(match key {
+            MANDATORY if !value.is_empty() && value.len().is_multiple_of(2) => {
+                SvcParam::Mandatory(value.chunks(2).map(|k| u16::from_be_bytes([k[0], k[1]])).collect())
+            }
+            ALPN => {
+                let mut ids = Vec::new();
+                let mut rest = &value[..];
+                while let [len, tail @ ..] = rest {
+                    let len = *len as usize;
+                    if len == 0 || tail.len() < len {
+                        return Err(invalid());
+                    }
+                    ids.push(tail[..len].to_vec());
+                    rest = &tail[len..];
+                }
+                if ids.is_empty() {
+                    return Err(invalid());
+                }
+                SvcParam::Alpn(ids)
+            }
+            NO_DEFAULT_ALPN if value.is_empty() => SvcParam::NoDefaultAlpn,
+            PORT if value.len() == 2 => SvcParam::Port(u16::from_be_bytes([value[0], value[1]])),
+            IPV4HINT if !value.is_empty() && value.len().is_multiple_of(4) => {
+                SvcParam::Ipv4Hint(value.chunks(4).map(|ip| Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap())).collect())
+            }
+            ECH => SvcParam::Ech(value),
+            IPV6HINT if !value.is_empty() && value.len().is_multiple_of(16) => {
+                SvcParam::Ipv6Hint(value.chunks(16).map(|ip| Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())).collect())
+            }
+            MANDATORY | NO_DEFAULT_ALPN | PORT | IPV4HINT | IPV6HINT => return Err(invalid()),
+            key => SvcParam::Unknown(key, value),
+        })
+    }
+}
+
+impl Display for SvcParam {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        fn join<T: ToString>(items: impl Iterator<Item = T>) -> String {
+            items.map(|i| i.to_string()).collect::<Vec<_>>().join(",")
+        }
+
+        let key = key_name(self.key());
+        match self {
+            SvcParam::Mandatory(keys) => write!(f, "{}={}", key, join(keys.iter().map(|k| key_name(*k)))),
+            SvcParam::Alpn(ids) => {
+                let ids: Vec<String> = ids
+                    .iter()
+                    .map(|id| {
+                        let mut output = String::new();
+                        id.iter().for_each(|&b| escape(b, b",\"", &mut output));
+                        output
+                    })
+                    .collect();
+                match ids.join(",") {
+                    ids if ids.contains(' ') => write!(f, "{}=\"{}\"", key, ids),
+                    ids => write!(f, "{}={}", key, ids),
+                }
+            }
+            SvcParam::NoDefaultAlpn => write!(f, "{}", key),
+            SvcParam::Port(port) => write!(f, "{}={}", key, port),
+            SvcParam::Ipv4Hint(ips) => write!(f, "{}={}", key, join(ips.iter())),
+            SvcParam::Ech(ech) => write!(f, "{}={}", key, BASE64.encode(ech)),
+            SvcParam::Ipv6Hint(ips) => write!(f, "{}={}", key, join(ips.iter())),
+            SvcParam::Unknown(_, value) if value.is_empty() => write!(f, "{}", key),
+            SvcParam::Unknown(_, value) => {
+                let mut output = String::new();
+                value.iter().for_each(|&b| escape(b, b"\"", &mut output));
+                write!(f, "{}=\"{}\"", key, output)
+            }
+        }
+    }
+}
+
+/// RDATA shared by SVCB and HTTPS. Priority 0 is AliasMode, anything else is ServiceMode.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Svcb {
+    pub priority: u16,
+    /// Empty for ".", which means the owner name itself in ServiceMode
+    pub target: DNSName,
+    /// Kept sorted by key, which is the order required on the wire
+    pub params: Vec<SvcParam>,
+}
+
+impl Svcb {
+    pub fn new(priority: u16, target: DNSName, mut params: Vec<SvcParam>) -> anyhow::Result<Self> {
+        params.sort_by_key(SvcParam::key);
+        if params.windows(2).any(|p| p[0].key() == p[1].key()) {
+            return Err(anyhow!("Duplicate SvcParamKey"));
+        }
+        // The keys mandatory lists go on the wire in order too
+        if let Some(SvcParam::Mandatory(keys)) = params.first_mut() {
+            keys.sort();
+        }
+        check_mandatory(&params).map_err(|err| anyhow!(err))?;
+        Ok(Svcb { priority, target, params })
+    }
+}
+
+/// RFC 9460 section 8: `mandatory` lists keys in strictly increasing order, can't list itself, and every key it lists
+/// has to be present
+fn check_mandatory(params: &[SvcParam]) -> Result<(), String> {
+    let Some(SvcParam::Mandatory(keys)) = params.iter().find(|p| p.key() == MANDATORY) else {
+        return Ok(());
+    };
+    if keys.windows(2).any(|k| k[0] >= k[1]) {
+        return Err("mandatory keys have to be in strictly increasing order, without duplicates".to_string());
+    }
+    if keys.contains(&MANDATORY) {
+        return Err("mandatory can't list itself".to_string());
+    }
+    match keys.iter().find(|&&key| params.iter().all(|p| p.key() != key)) {
+        Some(&key) => Err(format!("mandatory lists {}, which isn't there", key_name(key))),
+        None => Ok(()),
+    }
+}
+
+impl FromStr for Svcb {
+    type Err = anyhow::Error;
+
+    /// Parses RDATA like `1 . alpn=h3,h2 port=443 ipv4hint=192.0.2.1`
+    fn from_str(rdata: &str) -> Result<Self, Self::Err> {
+        let mut fields = rdata.split_whitespace();
+        let priority = fields.next().context("Missing SvcPriority")?.parse().context("Invalid SvcPriority")?;
+        let target = DNSName::from_str(fields.next().context("Missing TargetName")?).context("Invalid TargetName")?;
+        let params = split_params(fields.remainder().unwrap_or_default())?;
+        let params = params.into_iter().map(SvcParam::from_text).collect::<anyhow::Result<_>>()?;
+        Svcb::new(priority, target, params)
+    }
+}
+
+impl Display for Svcb {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.priority, self.target.to_fqdn())?;
+        for param in &self.params {
+            write!(f, " {}", param)?;
+        }
+        Ok(())
+    }
+}
+
+impl Svcb {
+    pub(crate) fn write(&self, writer: &mut Writer<'_>) -> Result<(), WireError> {
+        writer.u16(self.priority);
+        // TargetName is never compressed (RFC 9460 section 2.2)
+        self.target.write(writer, false)?;
+        for param in &self.params {
+            writer.u16(param.key());
+            writer.length_prefixed("SvcParam", |writer| {
+                writer.bytes(&param.value_bytes()?);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Reads SvcParams until the end of the RDATA
+    pub(crate) fn read(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        let priority = reader.u16()?;
+        let target = DNSName::read_uncompressed(reader)?;
+
+        let mut params: Vec<SvcParam> = Vec::new();
+        while !reader.is_empty() {
+            let key = reader.u16()?;
+            let len = reader.u16()?;
+            let value = reader.bytes(len as usize)?.to_vec();
+
+            if params.last().is_some_and(|last| last.key() >= key) {
+                return Err(WireError::Parse("SvcParamKeys not in strictly increasing order".into()));
+            }
+            params.push(SvcParam::from_bytes(key, value)?);
+        }
+        check_mandatory(&params).map_err(WireError::Parse)?;
+
+        Ok(Svcb { priority, target, params })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_roundtrip() {
+        let text = "1 . alpn=h3,h2 port=8443 ipv4hint=192.0.2.1,192.0.2.2 ech=AEX+DQBB ipv6hint=2001:db8::1 mandatory=alpn,port no-default-alpn key65000=\"x\"";
+        let svcb = Svcb::from_str(text).unwrap();
+
+        // Params are sorted by key
+        assert_eq!(
+            svcb.to_string(),
+            "1 . mandatory=alpn,port alpn=h3,h2 no-default-alpn port=8443 ipv4hint=192.0.2.1,192.0.2.2 ech=AEX+DQBB ipv6hint=2001:db8::1 key65000=\"x\""
+        );
+        assert_eq!(Svcb::from_str(&svcb.to_string()).unwrap(), svcb);
+
+        // Values of unknown keys are escaped like character-strings
+        let svcb = Svcb::from_str(r#"1 . key65000="a\\b\000\255\"""#).unwrap();
+        assert_eq!(svcb.params, [SvcParam::Unknown(65000, b"a\\b\x00\xFF\"".to_vec())]);
+        assert_eq!(svcb.to_string(), r#"1 . key65000="a\\b\000\255\"""#);
+        assert_eq!(Svcb::from_str(&svcb.to_string()).unwrap(), svcb);
+    }
+
+    #[test]
+    fn test_wire_roundtrip() {
+        let svcb = Svcb::from_str("1 svc.example.com alpn=h3,h2 port=443 ipv6hint=2001:db8::1 ech=AEX+DQBB").unwrap();
+
+        let mut output = Vec::new();
+        svcb.write(&mut Writer::new(&mut output)).unwrap();
+
+        let bytes = output.as_slice();
+        assert_eq!(bytes[..2], [0, 1]);
+        // alpn: key 1, length 6, "h3" "h2"
+        assert_eq!(bytes[19..29], [0, 1, 0, 6, 2, b'h', b'3', 2, b'h', b'2']);
+
+        let mut reader = Reader::detached(&output);
+        let parsed = Svcb::read(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(parsed, svcb);
+    }
+
+    #[test]
+    fn test_lists_roundtrip() {
+        for text in [
+            "1 . alpn=h2",
+            "1 . mandatory=alpn alpn=h3,h2",
+            "1 . ipv4hint=192.0.2.1",
+            "1 . ipv6hint=2001:db8::1,::1",
+        ] {
+            let svcb = Svcb::from_str(text).unwrap();
+            let mut output = Vec::new();
+            svcb.write(&mut Writer::new(&mut output)).unwrap();
+            let parsed = Svcb::read(&mut Reader::detached(&output)).unwrap();
+            assert_eq!(parsed.to_string(), text);
+        }
+
+        let long = "x".repeat(256);
+        assert!(Svcb::from_str(&format!("1 . alpn={}", long)).is_err());
+        assert!(Svcb::from_str(&format!("1 . alpn={}", &long[1..])).is_ok());
+        let svcb = Svcb::new(1, DNSName::from_url("svc.example.com"), vec![SvcParam::Alpn(vec![long.into_bytes()])]).unwrap();
+        assert!(svcb.write(&mut Writer::new(&mut Vec::new())).is_err());
+
+        // Each of these would go on the wire as something that can't be read back
+        for text in [
+            "1 . alpn=",
+            "1 . alpn=,,h2",
+            "1 . alpn=h2,",
+            "1 . ipv4hint=",
+            "1 . ipv6hint=",
+            "1 . mandatory= port=1",
+        ] {
+            assert!(Svcb::from_str(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_alpn_escapes() {
+        // A comma and a space inside ids, and an id that isn't UTF-8
+        let svcb = Svcb::from_str(r#"1 . alpn="h\,2,h 3,\255" port=443"#).unwrap();
+        assert_eq!(svcb.params[0], SvcParam::Alpn(vec![b"h,2".to_vec(), b"h 3".to_vec(), vec![255]]));
+        assert_eq!(svcb.to_string(), r#"1 . alpn="h\,2,h 3,\255" port=443"#);
+        assert_eq!(Svcb::from_str(&svcb.to_string()).unwrap(), svcb);
+
+        let mut output = Vec::new();
+        svcb.write(&mut Writer::new(&mut output)).unwrap();
+        let parsed = Svcb::read(&mut Reader::detached(&output)).unwrap();
+        assert_eq!(parsed, svcb);
+
+        assert!(Svcb::from_str(r#"1 . alpn="h2 port=443"#).is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Svcb::from_str("1 . port=1 port=2").is_err());
+        assert!(Svcb::from_str("1 . port=abc").is_err());
+        assert!(Svcb::from_str("1 . no-default-alpn=1").is_err());
+        assert!(Svcb::from_str("1 . bogus=1").is_err());
+
+        // Keys out of order on the wire
+        let input = [0, 1, 0, 0, 3, 0, 2, 1, 187, 0, 1, 0, 3, 2, b'h', b'2'];
+        assert!(Svcb::read(&mut Reader::detached(&input)).is_err());
+    }
+
+    #[test]
+    fn test_mandatory() {
+        assert!(Svcb::from_str("1 . mandatory=port port=443").is_ok());
+        assert!(Svcb::from_str("1 . mandatory=port").is_err());
+        assert!(Svcb::from_str("1 . mandatory=mandatory,port port=443").is_err());
+
+        // The same on the wire: mandatory=port without a port, then mandatory listing itself
+        let input = [0, 1, 0, 0, 0, 0, 2, 0, 3];
+        assert!(Svcb::read(&mut Reader::detached(&input)).is_err());
+        let input = [0, 1, 0, 0, 0, 0, 4, 0, 0, 0, 3, 0, 3, 0, 2, 1, 187];
+        assert!(Svcb::read(&mut Reader::detached(&input)).is_err());
+        let input = [0, 1, 0, 0, 0, 0, 2, 0, 3, 0, 3, 0, 2, 1, 187];
+        assert!(Svcb::read(&mut Reader::detached(&input)).is_ok());
+
+        // Keys are sorted, and can only be listed once
+        let svcb = Svcb::from_str("1 . mandatory=port,alpn alpn=h2 port=443").unwrap();
+        assert_eq!(svcb.params[0], SvcParam::Mandatory(vec![ALPN, PORT]));
+        let mut output = Vec::new();
+        svcb.write(&mut Writer::new(&mut output)).unwrap();
+        assert_eq!(output[3..11], [0, 0, 0, 4, 0, 1, 0, 3]);
+        assert!(Svcb::from_str("1 . mandatory=port,port port=443").is_err());
+
+        // On the wire they have to be in order already: mandatory=port,alpn, then mandatory=port,port
+        let input = [0, 1, 0, 0, 0, 0, 4, 0, 3, 0, 1, 0, 1, 0, 3, 2, b'h', b'2', 0, 3, 0, 2, 1, 187];
+        assert!(Svcb::read(&mut Reader::detached(&input)).is_err());
+        let input = [0, 1, 0, 0, 0, 0, 4, 0, 3, 0, 3, 0, 3, 0, 2, 1, 187];
+        assert!(Svcb::read(&mut Reader::detached(&input)).is_err());
+    }
+}
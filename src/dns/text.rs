@@ -1,36 +1,208 @@
-use std::str::FromStr;
-use deku::bitvec::{BitSlice, BitVec, Msb0};
-use deku::{DekuError, DekuRead, DekuWrite};
-use crate::dns::name::{DNSName, DNSNameCtxRtype};
-use crate::dns::rtypes::RType;
+use anyhow::{anyhow, Context};
+use std::{
+    fmt::{Display, Formatter, Write},
+    str::FromStr,
+};
 
+use crate::dns::wire::{Reader, WireError, Writer};
+
+/// TXT RDATA: one or more character-strings of up to 255 bytes each (RFC 1035 section 3.3.14)
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct DNSText {
-    inner: DNSName,
+    strings: Vec<Vec<u8>>,
+}
+
+impl DNSText {
+    pub fn new(strings: Vec<Vec<u8>>) -> anyhow::Result<Self> {
+        if strings.is_empty() {
+            return Err(anyhow!("TXT records need at least one character-string"));
+        }
+        if let Some(s) = strings.iter().find(|s| s.len() > u8::MAX as usize) {
+            return Err(anyhow!("Character-string of {} bytes is longer than 255", s.len()));
+        }
+        Ok(DNSText { strings })
+    }
+
+    /// Splits `bytes` into as many 255 byte character-strings as needed, like long DKIM keys are published
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let strings = if bytes.is_empty() {
+            vec![Vec::new()]
+        } else {
+            bytes.chunks(u8::MAX as usize).map(<[u8]>::to_vec).collect()
+        };
+        DNSText { strings }
+    }
+
+    pub fn strings(&self) -> &[Vec<u8>] {
+        &self.strings
+    }
 }
 
 impl From<String> for DNSText {
     fn from(value: String) -> Self {
-        DNSText {
-            inner: DNSName(vec![value])
+        DNSText::from_bytes(value.as_bytes())
+    }
+}
+
+/// Decodes the character after a backslash: either `\DDD` (a decimal byte value) or `\X` for a literal X
+pub(crate) fn unescape(chars: &mut impl Iterator<Item = u8>) -> anyhow::Result<u8> {
+    let first = chars.next().context("Dangling '\\' at the end of the string")?;
+    if !first.is_ascii_digit() {
+        return Ok(first);
+    }
+
+    let mut value = u32::from(first - b'0');
+    for _ in 0..2 {
+        match chars.next() {
+            Some(digit) if digit.is_ascii_digit() => value = value * 10 + u32::from(digit - b'0'),
+            _ => return Err(anyhow!("'\\DDD' escapes need exactly three digits")),
         }
     }
+    u8::try_from(value).map_err(|_| anyhow!("'\\{}' is larger than 255", value))
 }
 
+/// Appends `byte` in presentation format, escaping `special` characters with a backslash and anything unprintable
+/// as `\DDD`
+pub(crate) fn escape(byte: u8, special: &[u8], output: &mut String) {
+    match byte {
+        b if special.contains(&b) || b == b'\\' => {
+            output.push('\\');
+            output.push(b as char);
+        }
+        b' '..=b'~' => output.push(byte as char),
+        _ => write!(output, "\\{:03}", byte).unwrap(),
+    }
+}
+
+impl FromStr for DNSText {
+    type Err = anyhow::Error;
+
+    /// Parses the master file format: whitespace separated character-strings, each either a bare word or
+    /// `"quoted with spaces"`, with `\"`, `\\` and `\DDD` escapes in both
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut strings = Vec::new();
+        let mut chars = s.bytes().peekable();
 
+        while let Some(&c) = chars.peek() {
+            if c.is_ascii_whitespace() {
+                chars.next();
+                continue;
+            }
+
+            let quoted = c == b'"';
+            if quoted {
+                chars.next();
+            }
+            let mut string = Vec::new();
+            let mut closed = !quoted;
+            while let Some(c) = chars.next() {
+                match c {
+                    b'\\' => string.push(unescape(&mut chars)?),
+                    b'"' if quoted => {
+                        closed = true;
+                        break;
+                    }
+                    c if !quoted && c.is_ascii_whitespace() => break,
+                    c => string.push(c),
+                }
+            }
+            if !closed {
+                return Err(anyhow!("Unterminated quoted string in '{}'", s));
+            }
+            strings.push(string);
+        }
 
-impl DekuWrite<DNSNameCtxRtype> for DNSText {
-    fn write(&self, output: &mut BitVec<u8, Msb0>, mut ctx: DNSNameCtxRtype) -> Result<(), deku::DekuError> {
-        // ctx.rtype = RType::TXT;
-        self.inner.write(output, ctx)
+        DNSText::new(strings)
     }
 }
 
-impl<'a> DekuRead<'a> for DNSText {
-    fn read(input: &'a BitSlice<u8, Msb0>, ctx: ()) -> Result<(&'a BitSlice<u8, Msb0>, Self), DekuError> {
-        let (input, inner) = DNSName::read(input, ctx)?;
-        Ok((input, Self {
-            inner
-        }))
+impl Display for DNSText {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let strings: Vec<String> = self
+            .strings()
+            .iter()
+            .map(|string| {
+                let mut output = String::from("\"");
+                string.iter().for_each(|&b| escape(b, b"\"", &mut output));
+                output.push('"');
+                output
+            })
+            .collect();
+        write!(f, "{}", strings.join(" "))
     }
-}
\ No newline at end of file
+}
+
+impl DNSText {
+    pub(crate) fn write(&self, writer: &mut Writer<'_>) {
+        // Character-strings are never compressed and have no terminator
+        for string in &self.strings {
+            writer.u8(string.len() as u8);
+            writer.bytes(string);
+        }
+    }
+
+    /// Reads character-strings until the end of the RDATA
+    pub(crate) fn read(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        let mut strings = Vec::new();
+        while !reader.is_empty() {
+            let len = reader.u8()?;
+            strings.push(reader.bytes(len as usize)?.to_vec());
+        }
+        if strings.is_empty() {
+            return Err(WireError::Parse("TXT RDATA without a character-string".into()));
+        }
+        Ok(Self { strings })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = DNSText::from_str(r#"v=spf1 "include:_spf.example.com ~all" "say \"hi\"" a\\b \255\010"#).unwrap();
+        assert_eq!(
+            text.strings(),
+            [
+                b"v=spf1".to_vec(),
+                b"include:_spf.example.com ~all".to_vec(),
+                b"say \"hi\"".to_vec(),
+                b"a\\b".to_vec(),
+                vec![255, 10],
+            ]
+        );
+        assert_eq!(
+            text.to_string(),
+            r#""v=spf1" "include:_spf.example.com ~all" "say \"hi\"" "a\\b" "\255\010""#
+        );
+        assert_eq!(DNSText::from_str(&text.to_string()).unwrap(), text);
+
+        assert_eq!(DNSText::from_str(r#""""#).unwrap().strings(), [Vec::<u8>::new()]);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(DNSText::from_str(r#""unterminated"#).is_err());
+        assert!(DNSText::from_str(r#"\25"#).is_err());
+        assert!(DNSText::from_str(r#"\256"#).is_err());
+        assert!(DNSText::from_str(&"a".repeat(256)).is_err());
+        assert!(DNSText::from_str("  ").is_err());
+    }
+
+    #[test]
+    fn test_empty() {
+        assert!(DNSText::new(Vec::new()).is_err());
+        assert!(DNSText::read(&mut Reader::detached(&[])).is_err());
+
+        // One empty character-string is fine
+        let text = DNSText::read(&mut Reader::detached(&[0])).unwrap();
+        assert_eq!(text, DNSText::from_bytes(b""));
+    }
+
+    #[test]
+    fn test_long_values_are_split() {
+        let text = DNSText::from("k".repeat(300));
+        assert_eq!(text.strings().iter().map(Vec::len).collect::<Vec<_>>(), [255, 45]);
+    }
+}
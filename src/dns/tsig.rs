@@ -0,0 +1,560 @@
+// Secret key transaction signatures (RFC 8945)
+
+use crate::dns::{
+    data::RData,
+    header::HEADER_LEN,
+    name::DNSName,
+    record::{DNSRecord, VecDNSRecord},
+    rtypes::RType,
+    wire::{Reader, WireError, Writer},
+};
+use anyhow::{anyhow, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{
+    digest::{KeyInit, Mac},
+    Hmac,
+};
+use sha2::{Sha256, Sha384, Sha512};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// TSIG records are always of class ANY with a TTL of 0
+const CLASS_ANY: u16 = 255;
+
+/// Seconds of clock difference we allow between the signer and us, as recommended by RFC 8945 10
+const DEFAULT_FUDGE: u16 = 300;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    pub fn name(self) -> DNSName {
+        let name = match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha384 => "hmac-sha384",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        };
+        name.parse().unwrap()
+    }
+
+    pub fn from_name(name: &DNSName) -> Option<Self> {
+        [TsigAlgorithm::HmacSha256, TsigAlgorithm::HmacSha384, TsigAlgorithm::HmacSha512]
+            .into_iter()
+            .find(|algorithm| &algorithm.name() == name)
+    }
+
+    fn mac_len(self) -> usize {
+        match self {
+            TsigAlgorithm::HmacSha256 => 32,
+            TsigAlgorithm::HmacSha384 => 48,
+            TsigAlgorithm::HmacSha512 => 64,
+        }
+    }
+
+    fn sign(self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            TsigAlgorithm::HmacSha256 => hmac::<Hmac<Sha256>>(secret, data).finalize().into_bytes().to_vec(),
+            TsigAlgorithm::HmacSha384 => hmac::<Hmac<Sha384>>(secret, data).finalize().into_bytes().to_vec(),
+            TsigAlgorithm::HmacSha512 => hmac::<Hmac<Sha512>>(secret, data).finalize().into_bytes().to_vec(),
+        }
+    }
+
+    /// Compares in constant time. A truncated `mac` is checked against the start of the full one.
+    fn verify(self, secret: &[u8], data: &[u8], mac: &[u8]) -> bool {
+        match self {
+            TsigAlgorithm::HmacSha256 => hmac::<Hmac<Sha256>>(secret, data).verify_truncated_left(mac).is_ok(),
+            TsigAlgorithm::HmacSha384 => hmac::<Hmac<Sha384>>(secret, data).verify_truncated_left(mac).is_ok(),
+            TsigAlgorithm::HmacSha512 => hmac::<Hmac<Sha512>>(secret, data).verify_truncated_left(mac).is_ok(),
+        }
+    }
+}
+
+fn hmac<M: Mac + KeyInit>(secret: &[u8], data: &[u8]) -> M {
+    let mut mac = <M as KeyInit>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac
+}
+
+/// Shared secret, known to both sides under the same name
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TsigKey {
+    pub name: DNSName,
+    pub algorithm: TsigAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    pub fn new(name: DNSName, algorithm: TsigAlgorithm, secret: Vec<u8>) -> Self {
+        TsigKey { name, algorithm, secret }
+    }
+
+    /// Key with a base64 secret, as `tsig-keygen` and `dig -y` write them
+    pub fn from_base64(name: &str, algorithm: TsigAlgorithm, secret: &str) -> anyhow::Result<Self> {
+        let secret = STANDARD.decode(secret).context("Invalid base64 in TSIG secret")?;
+        Ok(TsigKey::new(name.parse()?, algorithm, secret))
+    }
+}
+
+impl FromStr for TsigKey {
+    type Err = anyhow::Error;
+
+    /// `[algorithm:]name:secret`, like `dig -y`. The algorithm defaults to hmac-sha256.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, name, secret) = match s.split(':').collect::<Vec<_>>()[..] {
+            [name, secret] => (TsigAlgorithm::HmacSha256, name, secret),
+            [algorithm, name, secret] => {
+                let algorithm = TsigAlgorithm::from_name(&algorithm.parse()?).with_context(|| anyhow!("Unsupported TSIG algorithm {}", algorithm))?;
+                (algorithm, name, secret)
+            }
+            _ => return Err(anyhow!("TSIG key '{}' isn't [algorithm:]name:secret", s)),
+        };
+        TsigKey::from_base64(name, algorithm, secret)
+    }
+}
+
+/// Keys we accept signatures from, by name
+#[derive(Debug, Default)]
+pub struct KeyRing {
+    keys: HashMap<DNSName, TsigKey>,
+}
+
+impl KeyRing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_key(mut self, key: TsigKey) -> Self {
+        self.keys.insert(key.name.clone(), key);
+        self
+    }
+
+    pub fn get(&self, name: &DNSName) -> Option<&TsigKey> {
+        self.keys.get(name)
+    }
+}
+
+/// TSIG errors, sent in the record's error field with a NOTAUTH RCODE
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
+pub enum TsigError {
+    BadSig,
+    BadKey,
+    BadTime,
+    BadTrunc,
+}
+
+impl TsigError {
+    pub fn code(self) -> u16 {
+        match self {
+            TsigError::BadSig => 16,
+            TsigError::BadKey => 17,
+            TsigError::BadTime => 18,
+            TsigError::BadTrunc => 22,
+        }
+    }
+}
+
+/// TSIG RDATA
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Tsig {
+    pub algorithm: DNSName,
+    /// Seconds since the epoch, 48 bits on the wire
+    pub time_signed: u64,
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Vec<u8>,
+}
+
+/// Appends `name` in canonical form: uncompressed and lowercase (RFC 4034 6.2)
+fn write_canonical(name: &DNSName, output: &mut Vec<u8>) {
+    for label in name.iter() {
+        output.push(label.as_bytes().len() as u8);
+        output.extend(label.as_bytes().iter().map(u8::to_ascii_lowercase));
+    }
+    output.push(0);
+}
+
+impl Tsig {
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        write_canonical(&self.algorithm, &mut output);
+        output.extend(&self.time_signed.to_be_bytes()[2..]);
+        output.extend(self.fudge.to_be_bytes());
+        output.extend((self.mac.len() as u16).to_be_bytes());
+        output.extend(&self.mac);
+        output.extend(self.original_id.to_be_bytes());
+        output.extend(self.error.to_be_bytes());
+        output.extend((self.other.len() as u16).to_be_bytes());
+        output.extend(&self.other);
+        output
+    }
+
+    /// What gets signed after the message: the record without its MAC and original ID (RFC 8945 4.3.3)
+    fn write_variables(&self, key_name: &DNSName, output: &mut Vec<u8>) {
+        write_canonical(key_name, output);
+        output.extend(CLASS_ANY.to_be_bytes());
+        output.extend(0u32.to_be_bytes());
+        write_canonical(&self.algorithm, output);
+        output.extend(&self.time_signed.to_be_bytes()[2..]);
+        output.extend(self.fudge.to_be_bytes());
+        output.extend(self.error.to_be_bytes());
+        output.extend((self.other.len() as u16).to_be_bytes());
+        output.extend(&self.other);
+    }
+}
+
+/// Adds the TSIG record to the end of a finished message. Fails if ARCOUNT has no room for another record.
+fn append_record(writer: &mut Writer<'_>, key_name: &DNSName, tsig: &Tsig) -> Result<(), WireError> {
+    let arcount = u16::from_be_bytes([writer.message()[10], writer.message()[11]])
+        .checked_add(1)
+        .ok_or_else(|| WireError::InvalidParam("No room in ARCOUNT for the TSIG record".into()))?;
+    let rdata = tsig.to_bytes();
+    let mut name = Vec::new();
+    write_canonical(key_name, &mut name);
+    writer.bytes(&name);
+    writer.u16(RType::TSIG.into());
+    writer.u16(CLASS_ANY);
+    writer.u32(0);
+    writer.u16(rdata.len() as u16);
+    writer.bytes(&rdata);
+    writer.patch(10, &arcount.to_be_bytes());
+    Ok(())
+}
+
+impl Tsig {
+    pub(crate) fn write(&self, writer: &mut Writer<'_>) {
+        writer.bytes(&self.to_bytes());
+    }
+
+    pub(crate) fn read(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        // The algorithm name is never compressed
+        let algorithm = DNSName::read_uncompressed(reader)?;
+        let time = reader.array::<6>()?;
+        let fudge = reader.u16()?;
+        let mac_len = reader.u16()?;
+        let mac = reader.bytes(mac_len as usize)?.to_vec();
+        let original_id = reader.u16()?;
+        let error = reader.u16()?;
+        let other_len = reader.u16()?;
+        let other = reader.bytes(other_len as usize)?.to_vec();
+
+        let mut time_signed = [0; 8];
+        time_signed[2..].copy_from_slice(&time);
+        Ok(Tsig {
+            algorithm,
+            time_signed: u64::from_be_bytes(time_signed),
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+}
+
+/// Pulls the TSIG record out of a parsed additional section. It has to be the last record, and there can only be
+/// one (RFC 8945 5.1).
+pub(crate) fn from_additional(additional: &VecDNSRecord) -> Result<Option<DNSRecord>, WireError> {
+    let Some(position) = additional.iter().position(|r| r.rtype == RType::TSIG) else {
+        return Ok(None);
+    };
+    if position != additional.len() - 1 {
+        return Err(WireError::Parse("TSIG record isn't the last record".into()));
+    }
+    Ok(additional.last().cloned())
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Result of checking the TSIG on a query. Says who signed it, or what the signer got wrong, and is needed to sign
+/// the response.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RequestTsig {
+    key_name: DNSName,
+    tsig: Tsig,
+    key: Option<TsigKey>,
+    error: Option<TsigError>,
+}
+
+impl RequestTsig {
+    /// Checks the TSIG `record` that starts at `offset` in `message`, as its last record (RFC 8945 5.2). Only
+    /// malformed records are errors; bad signatures are reported by `error` and answered with NOTAUTH.
+    pub(crate) fn verify(message: &[u8], record: &DNSRecord, offset: usize, keys: &KeyRing, now: u64) -> anyhow::Result<Self> {
+        let RData::TSIG(tsig) = &record.rdata else {
+            return Err(anyhow!("Invalid TSIG RDATA"));
+        };
+        let mut request = RequestTsig {
+            key_name: record.name.clone(),
+            tsig: tsig.clone(),
+            key: None,
+            error: None,
+        };
+
+        let key = keys
+            .get(&record.name)
+            .filter(|key| TsigAlgorithm::from_name(&tsig.algorithm) == Some(key.algorithm));
+        let Some(key) = key else {
+            request.error = Some(TsigError::BadKey);
+            return Ok(request);
+        };
+        request.key = Some(key.clone());
+
+        // MACs can be truncated, but not below half the hash or 10 bytes (RFC 8945 5.2.2.1)
+        let full_len = key.algorithm.mac_len();
+        if tsig.mac.len() > full_len || tsig.mac.len() < (full_len / 2).max(10) {
+            return Err(anyhow!("TSIG MAC of {} bytes for {:?}", tsig.mac.len(), key.algorithm));
+        }
+
+        // The MAC covers the message as it was before the TSIG record was added
+        let mut data = message
+            .get(..offset)
+            .filter(|data| data.len() >= HEADER_LEN)
+            .context("TSIG record outside the message")?
+            .to_vec();
+        data[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        let arcount = u16::from_be_bytes([data[10], data[11]])
+            .checked_sub(1)
+            .context("TSIG record isn't counted in ARCOUNT")?;
+        data[10..12].copy_from_slice(&arcount.to_be_bytes());
+        tsig.write_variables(&record.name, &mut data);
+
+        request.error = if !key.algorithm.verify(&key.secret, &data, &tsig.mac) {
+            Some(TsigError::BadSig)
+        } else if now.abs_diff(tsig.time_signed) > u64::from(tsig.fudge) {
+            Some(TsigError::BadTime)
+        } else if tsig.mac.len() < full_len {
+            // We don't accept truncated MACs
+            Some(TsigError::BadTrunc)
+        } else {
+            None
+        };
+        Ok(request)
+    }
+
+    /// Name of the key the query was signed with, if the signature checked out
+    pub fn signer(&self) -> Option<&DNSName> {
+        self.error.is_none().then_some(&self.key_name)
+    }
+
+    pub fn error(&self) -> Option<TsigError> {
+        self.error
+    }
+
+    /// TSIG for the response to this query
+    pub(crate) fn response(&self, now: u64) -> ResponseTsig {
+        let mut tsig = Tsig {
+            algorithm: self.tsig.algorithm.clone(),
+            time_signed: now,
+            fudge: DEFAULT_FUDGE,
+            mac: Vec::new(),
+            original_id: self.tsig.original_id,
+            error: self.error.map_or(0, TsigError::code),
+            other: Vec::new(),
+        };
+        // Keep the request's time so the client can check our MAC, and tell it ours (RFC 8945 5.2.3)
+        if self.error == Some(TsigError::BadTime) {
+            tsig.time_signed = self.tsig.time_signed;
+            tsig.other = now.to_be_bytes()[2..].to_vec();
+        }
+
+        // Without a key or a valid MAC to go on, the response is sent unsigned (RFC 8945 5.3.2)
+        let key = self
+            .key
+            .clone()
+            .filter(|_| !matches!(self.error, Some(TsigError::BadKey | TsigError::BadSig)));
+        ResponseTsig {
+            key_name: self.key_name.clone(),
+            tsig,
+            key,
+            request_mac: self.tsig.mac.clone(),
+        }
+    }
+}
+
+/// TSIG to be added to a response once it's written
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ResponseTsig {
+    key_name: DNSName,
+    tsig: Tsig,
+    key: Option<TsigKey>,
+    request_mac: Vec<u8>,
+}
+
+impl ResponseTsig {
+    /// Size of the record `sign` will add
+    pub(crate) fn record_len(&self) -> usize {
+        let mac_len = self.key.as_ref().map_or(0, |key| key.algorithm.mac_len());
+        self.key_name.wire_len() + 10 + self.tsig.to_bytes().len() + mac_len
+    }
+
+    /// Signs the finished message and appends the TSIG record to it (RFC 8945 4.3.1)
+    pub(crate) fn sign(&self, writer: &mut Writer<'_>) -> Result<(), WireError> {
+        let message = writer.message();
+        let mut tsig = self.tsig.clone();
+        tsig.original_id = u16::from_be_bytes([message[0], message[1]]);
+        if let Some(key) = &self.key {
+            let mut data = (self.request_mac.len() as u16).to_be_bytes().to_vec();
+            data.extend(&self.request_mac);
+            data.extend(message);
+            tsig.write_variables(&self.key_name, &mut data);
+            tsig.mac = key.algorithm.sign(&key.secret, &data);
+        }
+
+        append_record(writer, &self.key_name, &tsig)
+    }
+}
+
+/// Signs a query, like a client would. Returns the request MAC, needed to check the response.
+#[cfg(test)]
+pub(crate) fn sign_query(message: &mut Vec<u8>, key: &TsigKey, time_signed: u64) -> Vec<u8> {
+    let mut tsig = Tsig {
+        algorithm: key.algorithm.name(),
+        time_signed,
+        fudge: DEFAULT_FUDGE,
+        mac: Vec::new(),
+        original_id: u16::from_be_bytes([message[0], message[1]]),
+        error: 0,
+        other: Vec::new(),
+    };
+    let mut data = message.clone();
+    tsig.write_variables(&key.name, &mut data);
+    tsig.mac = key.algorithm.sign(&key.secret, &data);
+    *message = rewrite(message, |writer| append_record(writer, &key.name, &tsig).unwrap());
+    tsig.mac
+}
+
+/// Copies a finished message into a writer and runs `write` to add to it
+#[cfg(test)]
+fn rewrite(message: &[u8], write: impl FnOnce(&mut Writer<'_>)) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut writer = Writer::new(&mut output);
+    writer.bytes(message);
+    write(&mut writer);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::question::Question;
+
+    // Query for example.com A
+    const QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x00\x01";
+    const NOW: u64 = 1_700_000_000;
+
+    fn key() -> TsigKey {
+        "hmac-sha256:transfer.example.com:c2VjcmV0IGtleSBmb3IgdGVzdHM=".parse().unwrap()
+    }
+
+    fn verify(message: &[u8], keys: &KeyRing, now: u64) -> anyhow::Result<RequestTsig> {
+        let question = Question::parse(message)?;
+        question.verify_tsig(message, keys, now)?.context("No TSIG")
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key: TsigKey = "transfer.example.com:c2VjcmV0IGtleSBmb3IgdGVzdHM=".parse().unwrap();
+        assert_eq!(key, self::key());
+        let key: TsigKey = "hmac-sha512:transfer.example.com:c2VjcmV0".parse().unwrap();
+        assert_eq!((key.algorithm, key.secret), (TsigAlgorithm::HmacSha512, b"secret".to_vec()));
+
+        for key in [
+            "transfer.example.com",
+            "hmac-md5:transfer.example.com:c2VjcmV0",
+            "transfer.example.com:not base64",
+        ] {
+            assert!(key.parse::<TsigKey>().is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let keys = KeyRing::new().with_key(key());
+        let mut query = QUERY.to_vec();
+        sign_query(&mut query, &key(), NOW);
+
+        let request = verify(&query, &keys, NOW + 10).unwrap();
+        assert_eq!(request.signer(), Some(&key().name));
+
+        assert_eq!(verify(&query, &keys, NOW + 301).unwrap().error(), Some(TsigError::BadTime));
+        assert_eq!(verify(&query, &KeyRing::new(), NOW).unwrap().error(), Some(TsigError::BadKey));
+        let other_secret = TsigKey::new(key().name, TsigAlgorithm::HmacSha256, b"other".to_vec());
+        assert_eq!(
+            verify(&query, &KeyRing::new().with_key(other_secret), NOW).unwrap().error(),
+            Some(TsigError::BadSig)
+        );
+        let other_algorithm = TsigKey {
+            algorithm: TsigAlgorithm::HmacSha512,
+            ..key()
+        };
+        assert_eq!(
+            verify(&query, &KeyRing::new().with_key(other_algorithm), NOW).unwrap().error(),
+            Some(TsigError::BadKey)
+        );
+
+        // Changing the question breaks the signature
+        query[13] = b'E';
+        query[14] = b'y';
+        assert_eq!(verify(&query, &keys, NOW).unwrap().error(), Some(TsigError::BadSig));
+    }
+
+    #[test]
+    fn test_sign_response() {
+        let keys = KeyRing::new().with_key(key());
+        let mut query = QUERY.to_vec();
+        let request_mac = sign_query(&mut query, &key(), NOW);
+        let request = verify(&query, &keys, NOW).unwrap();
+
+        let mut response = QUERY.to_vec();
+        response[2] |= 0x80;
+        let unsigned = response.clone();
+        let response = rewrite(&response, |writer| request.response(NOW).sign(writer).unwrap());
+        assert_eq!(response[10..12], [0, 1]);
+
+        // The MAC covers the request MAC, the response and the TSIG variables
+        let parsed = Question::parse(&response).unwrap();
+        let RData::TSIG(tsig) = &parsed.tsig.as_ref().unwrap().rdata else {
+            panic!()
+        };
+        let mut data = (request_mac.len() as u16).to_be_bytes().to_vec();
+        data.extend(request_mac);
+        data.extend(unsigned);
+        tsig.write_variables(&key().name, &mut data);
+        assert!(TsigAlgorithm::HmacSha256.verify(&key().secret, &data, &tsig.mac));
+        assert_eq!(response.len(), QUERY.len() + request.response(NOW).record_len());
+
+        // No room to count another additional record
+        let mut full = QUERY.to_vec();
+        full[10..12].copy_from_slice(&[0xFF, 0xFF]);
+        let mut output = Vec::new();
+        let mut writer = Writer::new(&mut output);
+        writer.bytes(&full);
+        assert!(request.response(NOW).sign(&mut writer).is_err());
+        assert_eq!(output, full);
+    }
+
+    #[test]
+    fn test_errors_are_unsigned() {
+        let mut query = QUERY.to_vec();
+        sign_query(&mut query, &key(), NOW);
+        let request = verify(&query, &KeyRing::new(), NOW).unwrap();
+
+        let response = rewrite(QUERY, |writer| request.response(NOW).sign(writer).unwrap());
+        let parsed = Question::parse(&response).unwrap();
+        let RData::TSIG(tsig) = &parsed.tsig.as_ref().unwrap().rdata else {
+            panic!()
+        };
+        assert_eq!((tsig.mac.len(), tsig.error), (0, 17));
+    }
+}
@@ -0,0 +1,276 @@
+// Wire format codec
+//
+// `Reader` decodes straight from the buffer a message was received into, and `Writer` appends a message to a buffer
+// that can be reused from one message to the next. Both work on whole bytes: every field in a DNS message is
+// byte-aligned apart from the header flags, which are unpacked by hand.
+
+use crate::dns::{compression::Compression, name::Label};
+use std::fmt::{Display, Formatter};
+
+/// Why a message couldn't be read or written
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum WireError {
+    /// The input ends in the middle of a field
+    Incomplete,
+    /// The input isn't valid
+    Parse(String),
+    /// A value can't be written, e.g. because it's too long for its length field
+    InvalidParam(String),
+}
+
+impl Display for WireError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WireError::Incomplete => f.write_str("Message ends in the middle of a field"),
+            WireError::Parse(reason) => write!(f, "Malformed message: {}", reason),
+            WireError::InvalidParam(reason) => write!(f, "Can't write message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// Cursor over a message. Decoded fields are owned copies, so nothing read from it keeps borrowing the message.
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    message: &'a [u8],
+    pos: usize,
+    /// Reads stop here, e.g. at the end of the RDATA being decoded, even if the message goes on
+    end: usize,
+    /// Whether names may contain compression pointers into `message`
+    pointers: bool,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(message: &'a [u8]) -> Self {
+        Reader {
+            message,
+            pos: 0,
+            end: message.len(),
+            pointers: true,
+        }
+    }
+
+    /// Reader for data that isn't part of a message, e.g. RDATA in the generic text format. Names in it can't be
+    /// compressed, since there's nothing for pointers to point into.
+    pub fn detached(data: &'a [u8]) -> Self {
+        Reader {
+            pointers: false,
+            ..Reader::new(data)
+        }
+    }
+
+    /// Offset from the start of the message
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Bytes left in the current field, or in the message outside of one
+    pub fn remaining(&self) -> usize {
+        self.end - self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.end
+    }
+
+    /// The next `len` bytes, borrowed from the message
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], WireError> {
+        if len > self.remaining() {
+            return Err(WireError::Incomplete);
+        }
+        let bytes = &self.message[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Everything up to the end of the current field
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.message[self.pos..self.end];
+        self.pos = self.end;
+        rest
+    }
+
+    /// Everything read since `start`
+    pub fn since(&self, start: usize) -> &'a [u8] {
+        &self.message[start..self.pos]
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], WireError> {
+        Ok(self.bytes(N)?.try_into().expect("bytes returns exactly N bytes"))
+    }
+
+    pub fn u8(&mut self) -> Result<u8, WireError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, WireError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, WireError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    /// Runs `read` on the next `len` bytes only, like the RDATA of a record. Anything `read` leaves is skipped, so
+    /// it has to check for leftovers itself.
+    pub fn limited<T>(&mut self, len: usize, read: impl FnOnce(&mut Self) -> Result<T, WireError>) -> Result<T, WireError> {
+        if len > self.remaining() {
+            return Err(WireError::Incomplete);
+        }
+        let end = std::mem::replace(&mut self.end, self.pos + len);
+        let result = read(self);
+        self.pos = self.end;
+        self.end = end;
+        result
+    }
+
+    /// Reader at `offset` from the start of the message, to follow a compression pointer. `None` if the data being
+    /// read isn't a message.
+    pub(crate) fn jump(&self, offset: usize) -> Option<Reader<'a>> {
+        self.pointers.then(|| Reader {
+            pos: offset.min(self.message.len()),
+            ..Reader::new(self.message)
+        })
+    }
+}
+
+/// Appends a message to a buffer. Compression pointers are offsets from where the message starts in the buffer, so
+/// it may already hold something else, like the TCP length prefix.
+#[derive(Debug)]
+pub struct Writer<'a> {
+    output: &'a mut Vec<u8>,
+    start: usize,
+    compression: Compression,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(output: &'a mut Vec<u8>) -> Self {
+        let start = output.len();
+        Writer {
+            output,
+            start,
+            compression: Compression::new(),
+        }
+    }
+
+    /// Name compression is on by default. Turning it off writes every name in full.
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compression.set_enabled(enabled);
+        self
+    }
+
+    /// Bytes of the message written so far
+    pub fn position(&self) -> usize {
+        self.output.len() - self.start
+    }
+
+    /// The message written so far
+    pub fn message(&self) -> &[u8] {
+        &self.output[self.start..]
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.output.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    /// Writes a 16 bit length followed by whatever `write` writes, like RDLENGTH and the RDATA. The field is written
+    /// in place, so names inside it get their real message offsets. `what` names the field in errors.
+    pub fn length_prefixed(&mut self, what: &str, write: impl FnOnce(&mut Self) -> Result<(), WireError>) -> Result<(), WireError> {
+        let length_pos = self.position();
+        self.u16(0);
+        write(self)?;
+        let len = u16::try_from(self.position() - length_pos - 2)
+            .map_err(|_| WireError::InvalidParam(format!("{} is longer than {} bytes", what, u16::MAX)))?;
+        self.patch(length_pos, &len.to_be_bytes());
+        Ok(())
+    }
+
+    /// Overwrites bytes written earlier, `offset` bytes from the start of the message
+    pub fn patch(&mut self, offset: usize, bytes: &[u8]) {
+        let start = self.start + offset;
+        self.output[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Cuts the message back to its first `len` bytes, e.g. to drop records that don't fit. Names that were cut
+    /// can't be pointed to anymore.
+    pub fn truncate(&mut self, len: usize) {
+        self.output.truncate(self.start + len);
+        self.compression.truncate(len);
+    }
+
+    /// Offset of an earlier copy of `suffix` that a compression pointer can refer to
+    pub(crate) fn find_suffix(&self, suffix: &[Label]) -> Option<u16> {
+        self.compression.find(self.message(), suffix)
+    }
+
+    /// Lets later names point to the name suffix about to be written
+    pub(crate) fn add_suffix(&mut self) {
+        self.compression.add(self.position());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reader_bounds() {
+        let mut reader = Reader::new(&[0, 1, 2, 3, 4, 5]);
+        assert_eq!(reader.u16().unwrap(), 1);
+
+        // A field is read within its length, and whatever's left of it is skipped
+        let field = reader.limited(3, |field| {
+            let byte = field.u8()?;
+            assert_eq!(field.remaining(), 2);
+            assert_eq!(field.u32(), Err(WireError::Incomplete));
+            Ok(byte)
+        });
+        assert_eq!(field, Ok(2));
+        assert_eq!(reader.position(), 5);
+        assert_eq!(reader.since(2), [2, 3, 4]);
+
+        assert_eq!(reader.limited(2, |field| field.u8()), Err(WireError::Incomplete));
+        assert_eq!(reader.rest(), [5]);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_writer_offsets() {
+        // Something already in the buffer isn't part of the message
+        let mut output = vec![0xFF, 0xFF];
+        let mut writer = Writer::new(&mut output);
+        writer.u16(0x1234);
+        writer
+            .length_prefixed("test", |writer| {
+                writer.bytes(b"abc");
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(writer.position(), 7);
+        assert_eq!(writer.message(), [0x12, 0x34, 0, 3, b'a', b'b', b'c']);
+
+        writer.patch(0, &[0xAB]);
+        writer.truncate(2);
+        assert_eq!(output, [0xFF, 0xFF, 0xAB, 0x34]);
+
+        let mut writer = Writer::new(&mut output);
+        let too_long = writer.length_prefixed("test", |writer| {
+            writer.bytes(&[0; 0x10000]);
+            Ok(())
+        });
+        assert!(matches!(too_long, Err(WireError::InvalidParam(_))));
+    }
+}
@@ -1,7 +1,12 @@
-// Automatically routes {num1}-{num2}-{num3}-{num4}.ip.henryn.ca to the IP address
+// Automatically routes {num1}.{num2}.{num3}.{num4}.ip.henryn.ca to the IPv4 address,
+// and {ipv6 with '-' for ':'}.ip.henryn.ca (e.g. 2001-db8--1.ip.henryn.ca) to the IPv6 address.
+// TXT queries for myaddr.ip.henryn.ca are answered with the client subnet the resolver sent.
 
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 use crate::dns::data::RData;
+use crate::dns::ecs::ClientSubnet;
+use crate::dns::ede::{EdeCode, ExtendedError};
 use crate::dns::header::Rcode;
 use crate::dns::name::DNSName;
 use crate::dns::question::DNSQuestion;
@@ -14,58 +19,167 @@ use crate::nameserver::records::ResponseSection;
 pub struct IPRouter {}
 
 impl IPRouter {
-    pub fn build_response(&mut self, id: u16, question: DNSQuestion, tcp: bool) -> Response {
-        /// Question MUST HAVE qtype = A, qname = {num1}-{num2}-{num3}-{num4}.ip.henryn.ca
+    /// Lookups are public, so they're answered whoever `_signer` is
+    pub fn build_response(
+        &mut self,
+        id: u16,
+        question: DNSQuestion,
+        client_subnet: Option<&ClientSubnet>,
+        _signer: Option<&DNSName>,
+        tcp: bool,
+    ) -> Response {
+        /// Question MUST HAVE qtype = A or AAAA, qname = {num1}.{num2}.{num3}.{num4}.ip.henryn.ca or {ipv6}.ip.henryn.ca
         /// id is the question ID of the query that we must propagate to the response
-        println!("Got question {id} {question:?}");
+        println!("Got question {id} {question}");
 
         let qname = &question.qname;
         if !(qname.len() >= 3 && &qname[qname.len() - 3..] == ["ip", "henryn", "ca"]) {
-            return OwnedRecordItem::empty(id, question, tcp);
+            // NXDOMAIN would claim the name doesn't exist, which isn't ours to say
+            let mut response = Response::from_rcode(id, question, Rcode::Refused, tcp);
+            response.add_extended_error(ExtendedError::new(EdeCode::NotAuthoritative, "Only ip.henryn.ca is served here"));
+            return response;
         };
 
-        if qname.len() == 3 {
-            // Direct it to the base domain
-            const BASE_DOMAIN_IP: [u8; 4] = [10, 0, 0, 1];
+        if qname.len() == 3 && question.qtype == RType::SOA {
             let record = OwnedRecordItem {
-                record: DNSRecord {
-                    name: question.qname.clone(),
-                    rtype: RType::A,
-                    class: 1,
-                    ttl: 0,
-                    rdata: RData::Vec(BASE_DOMAIN_IP.to_vec()),
-                },
+                record: Self::soa(),
                 section: ResponseSection::Answer,
             };
             return OwnedRecordItem::build_response(&[record], id, question, tcp);
         }
 
-        if qname.len() != 7 {
-            let mut response = OwnedRecordItem::empty(id, question, tcp);
-            response.set_return_code(Rcode::Refused);
-            return response;
+        if qname.len() == 3 {
+            // Direct it to the base domain
+            const BASE_DOMAIN_IP: [u8; 4] = [10, 0, 0, 1];
+            return Self::respond(RData::A(Ipv4Addr::from(BASE_DOMAIN_IP)), id, question, tcp);
         }
 
-        let ips = &question.qname[0..4];
-        let ips_int: Vec<Option<u8>> = ips.iter().map(|x| u8::from_str(x).ok()).collect();
+        if qname.len() == 4 && qname[0] == "myaddr" {
+            return Self::myaddr(id, question, client_subnet, tcp);
+        }
 
-        if ips_int.iter().all(Option::is_some) {
-            let ips_int: Vec<u8> = ips_int.into_iter().map(Option::unwrap).collect();
-            let record = OwnedRecordItem {
-                record: DNSRecord {
-                    name: question.qname.clone(),
-                    rtype: RType::A,
-                    class: 1,
-                    ttl: 0,
-                    rdata: RData::Vec(ips_int),
-                },
-                section: ResponseSection::Answer,
-            };
-            OwnedRecordItem::build_response(&[record], id, question, tcp)
-        } else {
-            let mut response = OwnedRecordItem::empty(id, question, tcp);
-            response.set_return_code(Rcode::Refused);
-            return response;
+        let rdata = match &qname[..qname.len() - 3] {
+            ips @ [_, _, _, _] => {
+                let ips_int: Vec<Option<u8>> = ips.iter().map(|x| x.as_str().and_then(|x| u8::from_str(x).ok())).collect();
+                match ips_int[..] {
+                    [Some(a), Some(b), Some(c), Some(d)] => Some(RData::A(Ipv4Addr::new(a, b, c, d))),
+                    _ => None,
+                }
+            }
+            // ':' isn't allowed in labels, so IPv6 addresses are written with '-' instead
+            [ip] => ip.as_str().and_then(|ip| Ipv6Addr::from_str(&ip.replace('-', ":")).ok()).map(RData::AAAA),
+            _ => None,
+        };
+
+        match rdata {
+            Some(rdata) => Self::respond(rdata, id, question, tcp),
+            None => {
+                let text = format!("{} doesn't encode an IPv4 or IPv6 address", question.qname);
+                let mut response = OwnedRecordItem::empty(id, question, tcp);
+                response.set_return_code(Rcode::Refused);
+                response.add_extended_error(ExtendedError::new(EdeCode::Other, text));
+                response
+            }
+        }
+    }
+
+    /// SOA of the ip.henryn.ca zone, sent with negative answers so resolvers can cache them
+    fn soa() -> DNSRecord {
+        DNSRecord::try_from("ip.henryn.ca SOA ns1.henryn.ca hostmaster.henryn.ca 1 3600 600 86400 60").unwrap()
+    }
+
+    /// Which subnet the query was made for, like o-o.myaddr.l.google.com, to check what a resolver passes on. The
+    /// answer is only good for that exact subnet, so that's its scope.
+    fn myaddr(id: u16, question: DNSQuestion, client_subnet: Option<&ClientSubnet>, tcp: bool) -> Response {
+        let Some(subnet) = client_subnet else {
+            return OwnedRecordItem::negative(Rcode::NoError, Some(&Self::soa()), id, question, tcp);
+        };
+        let text = format!("edns0-client-subnet {}/{}", subnet.addr(), subnet.source_prefix());
+        let mut response = Self::respond(RData::TXT(text.into()), id, question, tcp);
+        response.set_client_subnet(subnet.with_scope(subnet.source_prefix()));
+        response
+    }
+
+    /// Answers with `rdata` if the question asked for its type. Otherwise the name exists but has nothing
+    /// of the requested type, so the answer is empty but not NXDOMAIN (e.g. AAAA queries for IPv4 names).
+    fn respond(rdata: RData, id: u16, question: DNSQuestion, tcp: bool) -> Response {
+        let rtype = rdata.rtype().unwrap();
+        if question.qtype != rtype {
+            return OwnedRecordItem::negative(Rcode::NoError, Some(&Self::soa()), id, question, tcp);
         }
+
+        let record = OwnedRecordItem {
+            record: DNSRecord {
+                name: question.qname.clone(),
+                rtype,
+                class: 1,
+                ttl: 0,
+                rdata,
+            },
+            section: ResponseSection::Answer,
+        };
+        OwnedRecordItem::build_response(&[record], id, question, tcp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::edns::Edns;
+
+    fn query(name: &str, qtype: RType) -> Response {
+        let mut response = IPRouter {}.build_response(1, DNSQuestion::new(DNSName::from_url(name), qtype), None, None, false);
+        response.serialize().unwrap();
+        response
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        let response = query("2001-db8--1.ip.henryn.ca", RType::AAAA);
+        assert_eq!((response.header().rcode(), response.header().ancount()), (Rcode::NoError, 1));
+
+        let response = query("10.0.0.2.ip.henryn.ca", RType::A);
+        assert_eq!((response.header().rcode(), response.header().ancount()), (Rcode::NoError, 1));
+    }
+
+    #[test]
+    fn test_other_family_is_nodata() {
+        let response = query("10.0.0.2.ip.henryn.ca", RType::AAAA);
+        assert_eq!((response.header().rcode(), response.header().ancount()), (Rcode::NoError, 0));
+        assert_eq!(response.header().nscount(), 1);
+
+        let response = query("2001-db8--1.ip.henryn.ca", RType::A);
+        assert_eq!((response.header().rcode(), response.header().ancount()), (Rcode::NoError, 0));
+    }
+
+    #[test]
+    fn test_extended_errors() {
+        let question = DNSQuestion::new(DNSName::from_url("not-an-ip.ip.henryn.ca"), RType::A);
+        let mut response = IPRouter {}.build_response(1, question.clone(), None, None, false);
+        // Without an OPT record from the client there's nowhere to put it
+        let bytes = response.serialize().unwrap();
+        assert_eq!(bytes[3] & 0xF, 5);
+        assert_eq!(bytes[10..12], [0, 0]);
+
+        let mut response = IPRouter {}.build_response(1, question, None, None, false);
+        response.set_edns(&Edns::new(4096));
+        let bytes = response.serialize().unwrap();
+        let text = b"not-an-ip.ip.henryn.ca doesn't encode an IPv4 or IPv6 address";
+        let option = [&[0, 15], &(text.len() as u16 + 2).to_be_bytes()[..], &[0, 0], text].concat();
+        assert!(bytes.ends_with(&option));
+    }
+
+    #[test]
+    fn test_myaddr() {
+        let question = DNSQuestion::new(DNSName::from_url("myaddr.ip.henryn.ca"), RType::TXT);
+        let subnet = ClientSubnet::new([198, 51, 100, 0].into(), 24).unwrap();
+        let mut response = IPRouter {}.build_response(1, question.clone(), Some(&subnet), None, false);
+        assert_eq!(response.client_subnet(), Some(&subnet.with_scope(24)));
+        let bytes = response.serialize().unwrap();
+        assert!(bytes.ends_with(b"\x23edns0-client-subnet 198.51.100.0/24"));
+
+        // Nothing to report without a subnet
+        let response = IPRouter {}.build_response(1, question, None, None, false);
+        assert_eq!((response.header().rcode(), response.header().ancount()), (Rcode::NoError, 0));
     }
-}
\ No newline at end of file
+}
@@ -18,8 +18,10 @@ pub use ip::IPRouter;
 
 use std::collections::HashMap;
 use crate::dns::data::RData;
+use crate::dns::ecs::ClientSubnet;
+use crate::dns::ede::{EdeCode, ExtendedError};
 use crate::dns::header::Rcode;
-use crate::dns::name::DNSName;
+use crate::dns::name::{DNSName, Label};
 use crate::dns::question::DNSQuestion;
 use crate::dns::record::DNSRecord;
 use crate::dns::response::Response;
@@ -33,6 +35,8 @@ type Storage = HashMap<String, String>;
 #[derive(Default, Debug)]
 pub struct KvStore {
     inner: Storage,
+    /// TSIG key that writes must be signed with. Anyone can write without one.
+    write_key: Option<DNSName>,
 }
 
 
@@ -44,8 +48,26 @@ pub struct OwnedRecordItem {
 
 impl OwnedRecordItem {
     pub fn empty(id: u16, question: DNSQuestion, tcp: bool) -> Response {
-        Self::build_response(&[], id, question, tcp)
+        Self::negative(Rcode::NoError, None, id, question, tcp)
     }
+
+    /// Negative answer: NXDOMAIN if the name doesn't exist, NOERROR (NODATA) if it exists without records of the
+    /// asked type. `soa` is the zone's SOA record, which resolvers need to cache the answer (RFC 2308).
+    pub fn negative(rcode: Rcode, soa: Option<&DNSRecord>, id: u16, question: DNSQuestion, tcp: bool) -> Response {
+        let list: Vec<Self> = soa
+            .map(|soa| OwnedRecordItem {
+                record: soa.to_negative_soa(),
+                section: ResponseSection::Authority,
+            })
+            .into_iter()
+            .collect();
+        let mut response = Self::build_response(&list, id, question, tcp);
+        response.set_return_code(rcode);
+        // Without the zone's SOA we can't claim to be its authority
+        response.header_mut().set_authoritative(soa.is_some());
+        response
+    }
+
     pub fn build_response(list: &[Self], id: u16, question: DNSQuestion, tcp: bool) -> Response {
         let mut answer = Vec::new();
         let mut authority = Vec::new();
@@ -59,17 +81,19 @@ impl OwnedRecordItem {
             }
         }
 
-        let rcode = if list.is_empty() {
-            Rcode::NxDomain
-        } else {
-            Rcode::NoError
-        };
-
-        Response::new(id, question, answer, authority, additional, tcp, rcode)
+        // Records built here come straight from our own data, so we're the authority for them
+        let mut response = Response::new(id, question, answer, authority, additional, tcp, Rcode::NoError);
+        response.header_mut().set_authoritative(true);
+        response
     }
 }
 
 impl KvStore {
+    pub fn with_write_key(mut self, key_name: DNSName) -> Self {
+        self.write_key = Some(key_name);
+        self
+    }
+
     pub fn query_put<'a>(&'a mut self, key: &str, value: &str, qtype: &'a RType) -> Vec<OwnedRecordItem> {
         let mut answer = Vec::new();
 
@@ -94,17 +118,17 @@ impl KvStore {
                 // Get the actual value
                 self.inner.get(name_str).map(|x| OwnedRecordItem {
                     record: DNSRecord {
-                        name: DNSName(vec![name_str.to_string()]),
+                        name: DNSName(vec![Label::from(name_str)]),
                         rtype: RType::TXT,
                         class: 1,
                         ttl: 0,
-                        rdata: RData::Text(DNSText::from(x.clone())),
+                        rdata: RData::TXT(DNSText::from(x.clone())),
                     },
                     section: ResponseSection::Answer,
                 }).map(|x| answer.push(x));
             }
             _ => {
-                // By sending an empty `answer`, automatically sends NXDOMAIN
+                // An empty `answer` becomes NODATA or NXDOMAIN depending on whether the key exists
             }
         };
         println!("Query get: {:?}", &answer);
@@ -142,14 +166,62 @@ impl KvStore {
             }
         };
 
+        if answer.is_empty() {
+            let rcode = if self.inner.contains_key(&question.qname[0].to_string()) {
+                Rcode::NoError
+            } else {
+                Rcode::NxDomain
+            };
+            return Ok(OwnedRecordItem::negative(rcode, None, id, question, tcp));
+        }
+
         Ok(OwnedRecordItem::build_response(&answer, id, question, tcp))
     }
 
-    pub fn build_response(&mut self, id: u16, question: DNSQuestion, tcp: bool) -> Response {
+    /// `signer` is the TSIG key the query was signed with, which writes need when a write key is set
+    pub fn build_response(
+        &mut self,
+        id: u16,
+        question: DNSQuestion,
+        _client_subnet: Option<&ClientSubnet>,
+        signer: Option<&DNSName>,
+        tcp: bool,
+    ) -> Response {
+        let is_write = question.qname.len() == 2;
+        if let Some(key) = self.write_key.as_ref().filter(|key| is_write && signer != Some(*key)) {
+            let mut response = Response::new(id, question, vec![], vec![], vec![], tcp, Rcode::Refused);
+            response.add_extended_error(ExtendedError::new(EdeCode::Prohibited, format!("Writes must be signed with key {}", key)));
+            return response;
+        }
+
         self.build_response_internal(id, question.clone(), tcp).unwrap_or_else(|err| {
             eprintln!("Error building response: {:?}", err);
-            Response::new(id, question,  vec![], vec![], vec![], tcp, Rcode::ServerFailure)
+            let mut response = Response::new(id, question,  vec![], vec![], vec![], tcp, Rcode::ServerFailure);
+            response.add_extended_error(ExtendedError::new(EdeCode::Other, err.to_string()));
+            response
         })
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_key() {
+        let key = DNSName::from_url("writer.example.com");
+        let mut kv = KvStore::default().with_write_key(key.clone());
+        let write = DNSQuestion::new(DNSName::from_url("foo.bar"), RType::TXT);
+
+        let response = kv.build_response(1, write.clone(), None, None, false);
+        assert_eq!(response.header().rcode(), Rcode::Refused);
+        let response = kv.build_response(1, write, None, Some(&key), false);
+        assert_eq!(response.header().rcode(), Rcode::NoError);
+
+        // Reads are open to anyone
+        let read = DNSQuestion::new(DNSName::from_url("foo"), RType::TXT);
+        let response = kv.build_response(1, read, None, None, false);
+        assert_eq!((response.header().rcode(), response.header().ancount()), (Rcode::NoError, 1));
+    }
+}
@@ -0,0 +1,25 @@
+#![feature(slice_pattern)]
+#![feature(async_closure)]
+#![feature(str_split_whitespace_remainder)]
+
+mod nameserver;
+
+mod dns;
+mod servers;
+mod utils;
+mod kv;
+
+pub use dns::{
+    cookie::ServerCookies,
+    question::Question,
+    response::Response,
+    tsig::{KeyRing, TsigKey},
+    wire::WireError,
+};
+pub use kv::KvStore;
+pub use nameserver::records::Records;
+pub use servers::{
+    shared::{AppData, Backend, ListenerConfig},
+    tcp::TcpServer,
+    udp::UdpServer,
+};
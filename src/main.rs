@@ -1,30 +1,70 @@
-#![feature(slice_pattern)]
-#![feature(async_closure)]
-#![feature(str_split_whitespace_remainder)]
-
-mod nameserver;
-
-mod dns;
-mod servers;
-mod utils;
-mod kv;
-
-use servers::{tcp::TcpServer, udp::UdpServer};
-use std::error::Error;
+use dns::{AppData, Backend, KeyRing, KvStore, ListenerConfig, Records, ServerCookies, TcpServer, TsigKey, UdpServer};
+use std::{
+    error::Error,
+    sync::{Arc, Mutex},
+};
 
 async fn server() -> Result<(), Box<dyn Error>> {
     const DNSADDR: &str = "0.0.0.0:53";
+    // UDP answers larger than a plain DNS message need a valid cookie, so we can't be used to amplify spoofed queries
+    const UNVERIFIED_UDP_LIMIT: u16 = 512;
 
-    let server = UdpServer::new(DNSADDR).await?;
-    let server1 = TcpServer::new(DNSADDR).await?;
+    // Shared so that cookies handed out over one transport are accepted on the other
+    let cookies = Arc::new(ServerCookies::new().with_required_above(UNVERIFIED_UDP_LIMIT));
+    // Keys to accept signed queries from, as space separated [algorithm:]name:secret
+    let keys = std::env::var("DNS_TSIG_KEYS")
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<anyhow::Result<Vec<TsigKey>>>()?;
+    // Once there are keys, writes to the KV store have to be signed with the first one
+    let mut kv = KvStore::default();
+    if let Some(key) = keys.first() {
+        kv = kv.with_write_key(key.name.clone());
+    }
+    let keys = keys.into_iter().fold(KeyRing::new(), KeyRing::with_key);
+    // ip (the default), records or kv
+    let backend = std::env::var("DNS_BACKEND").map_or(Ok(Backend::default()), |backend| backend.parse())?;
+    let mut records = Records::predefined();
+    // Answer reverse lookups for every address in the zone
+    if env_flag("DNS_REVERSE_SYNTHESIS") {
+        records = records.with_reverse_synthesis();
+    }
 
-    let res = tokio::try_join!(server.run(), server1.run());
+    let app = AppData {
+        backend,
+        records: Arc::new(records),
+        kv: Arc::new(Mutex::new(kv)),
+        cookies,
+        keys: Arc::new(keys),
+        // Plain DNS, so never padded
+        listener: ListenerConfig::default(),
+    };
+    let server = UdpServer::new(DNSADDR, app.clone()).await?;
+    let server1 = TcpServer::new(DNSADDR, app.clone()).await?;
+    // DNS over TLS, with TLS handled by a proxy in front of us that passes queries on here as plain TCP
+    let tls_server = match std::env::var("DNS_TLS_PROXY_ADDR") {
+        Ok(addr) => Some(TcpServer::new(&addr, AppData { listener: ListenerConfig::encrypted(), ..app }).await?),
+        Err(_) => None,
+    };
+
+    let res = tokio::try_join!(server.run(), server1.run(), async {
+        match &tls_server {
+            Some(server) => server.run().await,
+            None => Ok(()),
+        }
+    });
 
     println!("Server crashed: {:?}", res);
 
     Ok(())
 }
 
+/// Whether the variable is set to something other than 0
+fn env_flag(name: &str) -> bool {
+    std::env::var_os(name).is_some_and(|v| !v.is_empty() && v != "0")
+}
+
 #[tokio::main]
 async fn main() {
     server().await.unwrap();
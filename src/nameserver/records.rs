@@ -1,11 +1,13 @@
 use crate::{
     dns::{
+        data::RData,
         name::{DNSName, NameCmp},
         record::DNSRecord,
         rtypes::RType,
     },
     nameserver::default_records::DEFAULT_RECORDS,
 };
+use std::net::IpAddr;
 
 #[derive(Default, Debug, Clone)]
 pub struct Records {
@@ -37,6 +39,40 @@ impl Records {
         DEFAULT_RECORDS.iter().copied().map(DNSRecord::try_from).map(Result::unwrap).collect()
     }
 
+    /// Adds a PTR record under in-addr.arpa / ip6.arpa for every A and AAAA record, so reverse lookups work without
+    /// maintaining reverse zones by hand. Addresses that already have a PTR record are left alone.
+    pub fn with_reverse_synthesis(mut self) -> Self {
+        let synthesized: Vec<DNSRecord> = self
+            .inner
+            .iter()
+            .filter_map(|record| {
+                let ip = match record.rdata {
+                    RData::A(ip) => IpAddr::V4(ip),
+                    RData::AAAA(ip) => IpAddr::V6(ip),
+                    _ => return None,
+                };
+                Some(DNSRecord {
+                    name: DNSName::reverse_pointer(ip),
+                    rtype: RType::PTR,
+                    class: record.class,
+                    ttl: record.ttl,
+                    rdata: RData::PTR(record.name.clone()),
+                })
+            })
+            .collect();
+
+        for ptr in synthesized {
+            let exists = self
+                .inner
+                .iter()
+                .any(|r| r.rtype == RType::PTR && r.name.cmp(&ptr.name) == NameCmp::Equal);
+            if !exists {
+                self.inner.push(ptr);
+            }
+        }
+        self
+    }
+
     fn map_matching<'a>(&'a self, name: &'a DNSName) -> impl Iterator<Item = (&'a DNSRecord, NameCmp)> {
         self.inner.iter().filter_map(move |p| match p.name.cmp(name) {
             x @ (NameCmp::Equal | NameCmp::Subdomain | NameCmp::Superdomain) => Some((p, x)),
@@ -45,9 +81,12 @@ impl Records {
     }
 
     pub fn query<'a>(&'a self, name: &'a DNSName, qtype: &'a RType) -> impl Iterator<Item = RecordItem<'a>> {
+        let apex = self.zone_soa(name).map(|soa| &soa.name);
         self.map_matching(name).filter_map(move |(record, cmp)| {
+            // NS records at the zone apex are our own, not a delegation
+            let is_delegation = record.rtype == RType::NS && apex.is_none_or(|apex| record.name.cmp(apex) != NameCmp::Equal);
             match cmp {
-                NameCmp::Equal | NameCmp::Subdomain | NameCmp::Superdomain if record.rtype == RType::NS => Some(RecordItem {
+                NameCmp::Equal | NameCmp::Subdomain | NameCmp::Superdomain if is_delegation => Some(RecordItem {
                     record,
                     section: ResponseSection::Authority,
                 }),
@@ -70,10 +109,27 @@ impl Records {
         })
     }
 
+    /// SOA record of the closest zone containing `name`, if we're authoritative for it
+    pub fn zone_soa(&self, name: &DNSName) -> Option<&DNSRecord> {
+        self.inner
+            .iter()
+            .filter(|r| r.rtype == RType::SOA && matches!(r.name.cmp(name), NameCmp::Equal | NameCmp::Subdomain))
+            .max_by_key(|r| r.name.len())
+    }
+
+    /// Whether `name` owns any records, or is an empty non-terminal above names that do. Used to tell NODATA
+    /// apart from NXDOMAIN.
+    pub fn name_exists(&self, name: &DNSName) -> bool {
+        self.inner
+            .iter()
+            .any(|r| matches!(r.name.cmp(name), NameCmp::Equal | NameCmp::Superdomain))
+    }
+
     pub fn additional_section<'a>(&'a self, addl_name: &'a DNSName) -> impl Iterator<Item = RecordItem<'a>> {
-        // Do it if there was an NS record in the authority section
+        // Do it if there was an NS record in the authority section. Glue is both A and AAAA so dual-stack
+        // resolvers don't need another round trip.
         self.map_matching(addl_name).filter_map(|(record, cmp)| match cmp {
-            NameCmp::Equal if record.rtype == RType::A => Some(RecordItem {
+            NameCmp::Equal if matches!(record.rtype, RType::A | RType::AAAA) => Some(RecordItem {
                 record,
                 section: ResponseSection::Additional,
             }),
@@ -81,3 +137,30 @@ impl Records {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reverse_synthesis() {
+        let records: Records = [
+            "www.example.com A 10.0.0.1",
+            "www.example.com AAAA 2001:db8::1",
+            "mail.example.com A 10.0.0.1",
+        ]
+        .into_iter()
+        .map(|r| DNSRecord::try_from(r).unwrap())
+        .collect();
+        let records = records.with_reverse_synthesis();
+
+        let name = DNSName::from_url("1.0.0.10.in-addr.arpa");
+        let answers: Vec<_> = records.query(&name, &RType::PTR).map(|r| r.record.rdata.clone()).collect();
+        // Only the first owner of an address gets the PTR
+        assert_eq!(answers, [RData::PTR(DNSName::from_url("www.example.com"))]);
+
+        let name = DNSName::from_url("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa");
+        let answers: Vec<_> = records.query(&name, &RType::PTR).map(|r| r.record.rdata.clone()).collect();
+        assert_eq!(answers, [RData::PTR(DNSName::from_url("www.example.com"))]);
+    }
+}
@@ -1,3 +1,3 @@
-mod shared;
+pub(crate) mod shared;
 pub(crate) mod tcp;
 pub(crate) mod udp;
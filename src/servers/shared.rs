@@ -1,13 +1,21 @@
 use crate::{
-    dns::{header::Rcode, question::Question, response::Response, rtypes::RType},
+    dns::{
+        cookie::{Cookie, ServerCookies},
+        ecs::{ClientSubnet, OPTION_ECS},
+        ede::{EdeCode, ExtendedError},
+        edns::{Edns, ExtendedRcode, EDNS_VERSION, OPTION_COOKIE},
+        header::{Opcode, Rcode, HEADER_LEN},
+        json,
+        name::DNSName,
+        padding::PaddingPolicy,
+        question::{DNSQuestion, Question},
+        response::Response,
+        tsig::{unix_time, KeyRing, RequestTsig},
+    },
     kv::IPRouter,
     nameserver::records::Records,
-    utils::bv_to_vec,
 };
-use deku::{
-    bitvec::{BitSlice, BitVec}, DekuRead, DekuWrite,
-};
-use std::{future::Future, sync::Arc};
+use std::{future::Future, net::IpAddr, str::FromStr, sync::Arc};
 use std::sync::Mutex;
 use tokio::task::spawn_blocking;
 use crate::kv::KvStore;
@@ -15,73 +23,598 @@ use crate::kv::KvStore;
 
 #[derive(Clone)]
 pub struct AppData {
+    pub backend: Backend,
     pub records: Arc<Records>,
-    pub kv: Arc<Mutex<KvStore>>
+    pub kv: Arc<Mutex<KvStore>>,
+    pub cookies: Arc<ServerCookies>,
+    /// TSIG keys we accept signed queries from
+    pub keys: Arc<KeyRing>,
+    pub listener: ListenerConfig,
+}
+
+/// Where answers to standard queries come from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Addresses spelled out in names under ip.henryn.ca
+    #[default]
+    IpRouter,
+    /// The zone data in `AppData::records`
+    Records,
+    /// The key/value store in `AppData::kv`
+    Kv,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(Backend::IpRouter),
+            "records" => Ok(Backend::Records),
+            "kv" => Ok(Backend::Kv),
+            _ => Err(anyhow::anyhow!("Unknown backend '{}', expected ip, records or kv", s)),
+        }
+    }
+}
+
+/// Settings of the listener a query came in on
+#[derive(Clone, Copy, Default)]
+pub struct ListenerConfig {
+    /// TLS or HTTPS. Padding is only applied on encrypted transports, since it hides nothing in plain DNS.
+    pub encrypted: bool,
+    pub padding: PaddingPolicy,
+}
+
+impl ListenerConfig {
+    /// Listener for DNS over TLS or HTTPS, padding responses as RFC 8467 recommends
+    pub fn encrypted() -> Self {
+        ListenerConfig {
+            encrypted: true,
+            padding: PaddingPolicy::responses(),
+        }
+    }
 }
-fn handle_dns_packet1(ad: AppData, data: &[u8], tcp: bool) -> Vec<u8> {
-    let _records = ad.records;
-    let kv = ad.kv;
-    // Parse the DNS question from the packet
-    let bitslice = BitSlice::from_slice(data);
 
-    let dns_question = match Question::read(bitslice, tcp) {
-        Ok((_, dns_question)) => dns_question,
+/// Returns the response to send, or `None` if the packet should be dropped
+fn handle_dns_packet1(ad: AppData, data: &[u8], client: IpAddr, tcp: bool) -> Option<Vec<u8>> {
+    // Parse the DNS question from the packet
+    json::log(data);
+    let dns_question = match Question::parse(data) {
+        Ok(dns_question) => dns_question,
         Err(err) => {
             eprintln!("Failed to parse DNS question: {err:?}");
-            return vec![];
+            // Anything shorter than a header can't even be answered with an error
+            if data.len() < HEADER_LEN {
+                return None;
+            }
+            let id = u16::from_be_bytes([data[0], data[1]]);
+            return serialize(Response::format_error(id, tcp));
         }
     };
 
-    if matches!(dns_question.question.qtype, RType::Unknown(_)) {
-        eprintln!("Unknown qtype: {:?}", dns_question.question.qtype);
-        let response = Response::from_rcode(dns_question.header.id, dns_question.question, Rcode::NotImplemented, tcp);
-        let mut bitvec = BitVec::new();
-        response.write(&mut bitvec, tcp).unwrap();
-        return bv_to_vec(bitvec);
+    let query_header = dns_question.header().clone();
+    let tsig = match dns_question.verify_tsig(data, &ad.keys, unix_time()) {
+        Ok(tsig) => tsig,
+        Err(err) => {
+            eprintln!("Malformed TSIG record: {err:?}");
+            return serialize(Response::from_questions(query_header.id(), dns_question.questions, Rcode::FormatError, tcp));
+        }
+    };
+
+    let mut response = match tsig.as_ref().and_then(RequestTsig::error) {
+        // RFC 8945 5.2: a query that fails the check isn't looked at any further
+        Some(error) => {
+            eprintln!("TSIG check failed: {error:?}");
+            Response::from_questions(query_header.id(), dns_question.questions, Rcode::NotAuth, tcp)
+        }
+        None => {
+            let signer = tsig.as_ref().and_then(RequestTsig::signer);
+            respond(&ad, dns_question, client, signer, tcp)
+        }
+    };
+    // Every response to a query we could parse echoes its opcode, RD and CD
+    response.header_mut().copy_request_flags(&query_header);
+    if ad.listener.encrypted {
+        response.set_padding(ad.listener.padding);
     }
+    if let Some(tsig) = &tsig {
+        response.set_tsig(tsig.response(unix_time()));
+    }
+    serialize(response)
+}
 
-    println!("{dns_question:?}");
+/// `signer` is the TSIG key that signed the query, if it checked out
+fn respond(ad: &AppData, dns_question: Question, client: IpAddr, signer: Option<&DNSName>, tcp: bool) -> Response {
+    let cookies = &ad.cookies;
+    let id = dns_question.header().id();
+    let opcode = dns_question.header().opcode();
+    let edns = dns_question.edns;
 
-    // let mut response = Response::build_from_record_iter(dns_question.header.id, dns_question.question, &records, tcp);
-    let mut ip = IPRouter{};
-    // let mut response = kv.lock().unwrap().build_response(dns_question.header.id, dns_question.question, tcp);
-    let mut response = ip.build_response(dns_question.header.id, dns_question.question, tcp);
-    let mut bitvec = BitVec::new();
-    response.write(&mut bitvec, tcp).unwrap();
+    if let Some(edns) = edns.as_ref().filter(|edns| edns.version != EDNS_VERSION) {
+        // RFC 6891 6.1.3: answer queries for an EDNS version we don't know with BADVERS and nothing else
+        let mut response = Response::from_questions(id, dns_question.questions, Rcode::NoError, tcp);
+        response.set_edns(edns);
+        response.set_extended_return_code(ExtendedRcode::BadVers);
+        return response;
+    }
 
-    let total_len = (bitvec.as_raw_slice().len() - response.header.message_len_offset()).try_into().unwrap();
-    let updated = response.header.update_from_total_msg_len(total_len);
+    let cookie = match edns.as_ref().and_then(|edns| edns.option(OPTION_COOKIE)).map(Cookie::try_from).transpose() {
+        Ok(cookie) => cookie,
+        // RFC 7873 5.2.2
+        Err(err) => return malformed_option(err, id, dns_question.questions, edns.as_ref(), tcp),
+    };
+    let client_subnet = match edns.as_ref().and_then(|edns| edns.option(OPTION_ECS)).map(ClientSubnet::try_from).transpose() {
+        // Scope is for responses, so a query with one is malformed (RFC 7871 7.1.2)
+        Ok(Some(subnet)) if subnet.scope_prefix() != 0 => {
+            let err = anyhow::anyhow!("Client subnet query with scope /{}", subnet.scope_prefix());
+            return malformed_option(err, id, dns_question.questions, edns.as_ref(), tcp);
+        }
+        Ok(client_subnet) => client_subnet,
+        Err(err) => return malformed_option(err, id, dns_question.questions, edns.as_ref(), tcp),
+    };
 
-    if updated && response.header.tc > 0 {
-        response.clear();
-        bitvec = BitVec::new();
+    let verified = cookie.as_ref().is_some_and(|cookie| cookies.verify(cookie, client));
+    let udp_limit = cookies.required_above().filter(|_| !tcp && !verified);
 
-        // Set all lengths of responses to 0 except question
-        response.header.ancount = 0;
-        response.header.nscount = 0;
-        response.header.arcount = 0;
+    // A server cookie that isn't ours (or has expired) gets a fresh one to retry with (RFC 7873 5.2.3). Clients
+    // without one are answered normally below, just without anything large.
+    if let Some(cookie) = cookie.as_ref().filter(|cookie| udp_limit.is_some() && cookie.server.is_some()) {
+        let mut response = Response::from_questions(id, dns_question.questions, Rcode::NoError, tcp);
+        if let Some(edns) = &edns {
+            response.set_edns(edns);
+        }
+        response.set_extended_return_code(ExtendedRcode::BadCookie);
+        response.set_cookie(cookies.respond(cookie, client));
+        return response;
+    }
 
-        response.write(&mut bitvec, tcp).unwrap();
-    } else if updated {
-        let mut header_bv = BitVec::new();
+    // Only standard queries are supported. IQUERY is obsolete (RFC 3425), and STATUS, NOTIFY and UPDATE
+    // mean nothing to a server without zone transfers.
+    let mut response = if opcode != Opcode::Query {
+        eprintln!("Unsupported opcode: {:?}", opcode);
+        let mut response = Response::from_questions(id, dns_question.questions, Rcode::NotImplemented, tcp);
+        response.add_extended_error(ExtendedError::new(EdeCode::NotSupported, format!("Opcode {:?} isn't supported", opcode)));
+        response
+    } else {
+        // We answer exactly one question. A query without one is only valid to fetch our cookie (RFC 7873 5.4),
+        // and anything with more is malformed since no one knows how to answer it.
+        match <[DNSQuestion; 1]>::try_from(dns_question.questions) {
+            Ok([question]) if question.qtype.is_meta() => {
+                eprintln!("Unsupported qtype: {}", question.qtype);
+                let text = format!("{} queries aren't supported", question.qtype);
+                let mut response = Response::from_rcode(id, question, Rcode::NotImplemented, tcp);
+                response.add_extended_error(ExtendedError::new(EdeCode::NotSupported, text));
+                response
+            }
+            // Types we don't know are answered like any other (RFC 3597), usually with NODATA
+            Ok([question]) => {
+                println!("{}", question);
 
-        response.header.write(&mut header_bv, tcp).unwrap();
-        if response.header.tc > 0 {
-            bitvec = header_bv;
-        } else {
-            bitvec.splice(0..header_bv.len(), header_bv);
+                match ad.backend {
+                    Backend::IpRouter => IPRouter {}.build_response(id, question, client_subnet.as_ref(), signer, tcp),
+                    Backend::Records => Response::build_from_record_iter(id, question, &ad.records, client_subnet.as_ref(), tcp),
+                    Backend::Kv => ad.kv.lock().unwrap().build_response(id, question, client_subnet.as_ref(), signer, tcp),
+                }
+            }
+            Err(questions) => {
+                let rcode = if questions.is_empty() && cookie.is_some() { Rcode::NoError } else { Rcode::FormatError };
+                Response::from_questions(id, Vec::new(), rcode, tcp)
+            }
         }
+    };
+
+    if let Some(edns) = &edns {
+        response.set_edns(edns);
+    }
+    if let Some(cookie) = &cookie {
+        response.set_cookie(cookies.respond(cookie, client));
+    }
+    // Handlers that tailored the answer to the subnet have already set its scope. Anything else is good for every
+    // subnet (scope 0).
+    if let (Some(subnet), None) = (&client_subnet, response.client_subnet()) {
+        response.set_client_subnet(subnet.with_scope(0));
     }
+    if let Some(len) = udp_limit {
+        response.limit_udp_len(len);
+    }
+    response
+}
 
-    bv_to_vec(bitvec)
+/// FORMERR for a query with an EDNS option we can't make sense of
+fn malformed_option(err: anyhow::Error, id: u16, questions: Vec<DNSQuestion>, edns: Option<&Edns>, tcp: bool) -> Response {
+    eprintln!("Malformed EDNS option: {err:?}");
+    let mut response = Response::from_questions(id, questions, Rcode::FormatError, tcp);
+    response.add_extended_error(ExtendedError::new(EdeCode::Other, err.to_string()));
+    if let Some(edns) = edns {
+        response.set_edns(edns);
+    }
+    response
+}
+
+fn serialize(mut response: Response) -> Option<Vec<u8>> {
+    response
+        .serialize()
+        .inspect(|bytes| json::log(bytes))
+        .inspect_err(|err| eprintln!("Failed to serialize response: {err:?}"))
+        .ok()
 }
 
 pub async fn handle_dns_packet<F: FnOnce(Vec<u8>) -> T, T: Future<Output = std::io::Result<()>>>(
     records: AppData,
     data: Vec<u8>,
+    client: IpAddr,
     tcp: bool,
     send_callback: F,
 ) -> std::io::Result<()> {
-    let res = spawn_blocking(move || handle_dns_packet1(records, &data, tcp)).await.unwrap();
-    send_callback(res).await
+    match spawn_blocking(move || handle_dns_packet1(records, &data, client, tcp)).await {
+        Ok(Some(res)) => send_callback(res).await,
+        Ok(None) => Ok(()),
+        Err(err) => {
+            eprintln!("Handler task failed: {err:?}");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{
+        edns::EdnsOption,
+        tsig::{sign_query, TsigAlgorithm, TsigKey},
+    };
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn app_data() -> AppData {
+        AppData {
+            backend: Backend::default(),
+            records: Arc::new(Records::default()),
+            kv: Arc::new(Mutex::new(KvStore::default())),
+            cookies: Arc::new(ServerCookies::new()),
+            keys: Arc::new(KeyRing::new()),
+            listener: ListenerConfig::default(),
+        }
+    }
+
+    // Query for 10.0.0.2.ip.henryn.ca A with an OPT record
+    const QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x01\
+        \x0210\x010\x010\x012\x02ip\x06henryn\x02ca\x00\x00\x01\x00\x01\
+        \x00\x00\x29\x04\xd0\x00\x00\x00\x00\x00\x00";
+
+    #[test]
+    fn test_formerr() {
+        // Claims a question that isn't there
+        let response = handle_dns_packet1(app_data(), &QUERY[..12], CLIENT, false).unwrap();
+        assert_eq!(response, b"\x12\x34\x80\x01\x00\x00\x00\x00\x00\x00\x00\x00");
+
+        // Label length 64 is neither a label nor a pointer
+        let mut query = QUERY.to_vec();
+        query[12] = 64;
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 1);
+
+        assert_eq!(handle_dns_packet1(app_data(), &QUERY[..11], CLIENT, false), None);
+    }
+
+    #[test]
+    fn test_question_count() {
+        // No question and no cookie
+        let mut query = QUERY[..12].to_vec();
+        query[5] = 0;
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        assert_eq!(response, b"\x12\x34\x80\x01\x00\x00\x00\x00\x00\x00\x00\x00");
+
+        // Only a cookie
+        query[11] = 1;
+        query.extend(b"\x00\x00\x29\x04\xd0\x00\x00\x00\x00\x00\x0C\x00\x0A\x00\x08clientck");
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 0);
+        assert_eq!(response[4..12], [0, 0, 0, 0, 0, 0, 0, 1]);
+
+        // Two questions
+        let mut query = QUERY.to_vec();
+        query[5] = 2;
+        query.splice(12..12, b"\x03www\x00\x00\x01\x00\x01".iter().copied());
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 1);
+        assert_eq!(response[4..6], [0, 0]);
+    }
+
+    #[test]
+    fn test_unknown_qtype() {
+        let qtype_pos = QUERY.len() - 15;
+        let mut query = QUERY.to_vec();
+        query[qtype_pos..qtype_pos + 2].copy_from_slice(&65280u16.to_be_bytes());
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        // NOERROR, no answers and the SOA in authority
+        assert_eq!(response[3] & 0xF, 0);
+        assert_eq!(response[6..10], [0, 0, 0, 1]);
+
+        // AXFR is a meta type we can't answer
+        query[qtype_pos..qtype_pos + 2].copy_from_slice(&252u16.to_be_bytes());
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 4);
+    }
+
+    #[test]
+    fn test_request_flags() {
+        // RD and CD set
+        let mut query = QUERY.to_vec();
+        query[3] = 0x10;
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        // QR, AA and RD, then CD
+        assert_eq!(response[2..4], [0x85, 0x10]);
+
+        // RD clear, and outside our zone
+        let mut query = QUERY.to_vec();
+        query[2] = 0;
+        query.splice(12..35, b"\x07example\x03com\x00".iter().copied());
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[2..4], [0x80, 0x05]);
+        // EDE 20, Not Authoritative
+        assert!(response.ends_with(b"\x00\x14Only ip.henryn.ca is served here"));
+    }
+
+    #[test]
+    fn test_records_backend() {
+        let records: Records = [
+            "henryn.ca SOA ns1.henryn.ca hostmaster.henryn.ca 1 7200 3600 1209600 30",
+            "www.henryn.ca A 10.0.0.1",
+        ]
+        .into_iter()
+        .map(|r| crate::dns::record::DNSRecord::try_from(r).unwrap())
+        .collect();
+        let ad = AppData {
+            backend: Backend::Records,
+            records: Arc::new(records),
+            ..app_data()
+        };
+
+        // QUERY's name is in the zone but doesn't exist: NXDOMAIN from the authority, with the SOA to cache it by
+        let response = handle_dns_packet1(ad.clone(), QUERY, CLIENT, false).unwrap();
+        assert_eq!(response[2..4], [0x85, 0x03]);
+        assert_eq!(response[6..10], [0, 0, 0, 1]);
+
+        // An existing name without records of the type is NODATA
+        let mut query = QUERY.to_vec();
+        query.splice(12..35, b"\x03www\x06henryn\x02ca\x00".iter().copied());
+        let qtype_pos = query.len() - 15;
+        query[qtype_pos..qtype_pos + 2].copy_from_slice(&28u16.to_be_bytes());
+        let response = handle_dns_packet1(ad, &query, CLIENT, false).unwrap();
+        assert_eq!(response[2..4], [0x85, 0x00]);
+        assert_eq!(response[6..10], [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_opcodes() {
+        // IQUERY, STATUS and an unassigned opcode
+        for opcode in [1u8, 2, 15] {
+            let mut query = QUERY.to_vec();
+            query[2] = opcode << 3 | 0x01;
+            let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+            assert_eq!(response[2], 0x80 | opcode << 3 | 0x01);
+            assert_eq!(response[3] & 0xF, 4);
+            // The question is still echoed, and the OPT record says why
+            assert_eq!(response[4..6], [0, 1]);
+            let text = format!("Opcode {:?} isn't supported", Opcode::from(opcode));
+            assert!(response.ends_with(&[&[0, 21], text.as_bytes()].concat()));
+        }
+    }
+
+    /// QUERY with one option in its OPT record
+    fn with_option(code: u16, data: &[u8]) -> Vec<u8> {
+        let mut query = QUERY[..QUERY.len() - 2].to_vec();
+        query.extend((data.len() as u16 + 4).to_be_bytes());
+        query.extend(code.to_be_bytes());
+        query.extend((data.len() as u16).to_be_bytes());
+        query.extend(data);
+        query
+    }
+
+    fn with_cookie(cookie: &[u8]) -> Vec<u8> {
+        with_option(10, cookie)
+    }
+
+    #[test]
+    fn test_client_subnet() {
+        // 198.51.100.0/24, echoed back with scope 0 since our answers don't depend on it
+        let query = with_option(8, &[0, 1, 24, 0, 198, 51, 100]);
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 0);
+        assert!(response.ends_with(&[0, 8, 0, 7, 0, 1, 24, 0, 198, 51, 100]));
+
+        // Scope in a query
+        let query = with_option(8, &[0, 1, 24, 24, 198, 51, 100]);
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 1);
+        assert!(response.ends_with(b"\x00\x00Client subnet query with scope /24"));
+        assert_eq!(response[4..6], [0, 1]);
+
+        // myaddr.ip.henryn.ca TXT depends on the subnet, so the handler scopes it to the whole /24
+        let mut query = QUERY[..12].to_vec();
+        query.extend(b"\x06myaddr\x02ip\x06henryn\x02ca\x00\x00\x10\x00\x01");
+        query.extend(&with_option(8, &[0, 1, 24, 0, 198, 51, 100])[QUERY.len() - 11..]);
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[6..8], [0, 1]);
+        assert!(response.ends_with(&[0, 8, 0, 7, 0, 1, 24, 24, 198, 51, 100]));
+    }
+
+    #[test]
+    fn test_cookies() {
+        let ad = app_data();
+        let response = handle_dns_packet1(ad.clone(), &with_cookie(b"clientck"), CLIENT, false).unwrap();
+        // Our cookie comes last in the OPT record: the client cookie and a 16 byte server cookie
+        let (rest, option) = response.split_at(response.len() - 28);
+        assert_eq!(option[..12], *b"\x00\x0A\x00\x18clientck");
+        assert_eq!(rest[rest.len() - 2..], [0, 28]);
+
+        // Sent back from the same address it's valid, from another it's just ignored
+        let query = with_cookie(&option[4..]);
+        let response = handle_dns_packet1(ad.clone(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 0);
+        assert!(ad.cookies.verify(&Cookie::try_from(&EdnsOption::new(10, option[4..].to_vec())).unwrap(), CLIENT));
+        let response = handle_dns_packet1(ad, &query, IpAddr::from([10, 0, 0, 1]), false).unwrap();
+        assert_eq!(response[3] & 0xF, 0);
+
+        // Malformed
+        let response = handle_dns_packet1(app_data(), &with_cookie(b"short"), CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 1);
+        assert!(response.ends_with(b"\x00\x0F\x00\x27\x00\x00Cookie option of 5 bytes is malformed"));
+    }
+
+    #[test]
+    fn test_cookies_required() {
+        // Small enough that no answer fits without a cookie
+        let ad = AppData {
+            cookies: Arc::new(ServerCookies::new().with_required_above(40)),
+            ..app_data()
+        };
+
+        let response = handle_dns_packet1(ad.clone(), QUERY, CLIENT, false).unwrap();
+        assert_eq!(response[2] & 0x02, 0x02);
+        assert_eq!(response[6..8], [0, 0]);
+        // TCP is proof enough of the client's address
+        let response = handle_dns_packet1(ad.clone(), QUERY, CLIENT, true).unwrap();
+        assert_eq!(response[2] & 0x02, 0);
+
+        // Client cookie only: answered with a cookie to come back with, but still truncated
+        let response = handle_dns_packet1(ad.clone(), &with_cookie(b"clientck"), CLIENT, false).unwrap();
+        assert_eq!(response[2] & 0x02, 0x02);
+        let server_cookie = response[response.len() - 24..].to_vec();
+
+        let response = handle_dns_packet1(ad.clone(), &with_cookie(&server_cookie), CLIENT, false).unwrap();
+        assert_eq!(response[2] & 0x02, 0);
+        assert_eq!(response[6..8], [0, 1]);
+
+        // A server cookie we didn't make gets BADCOOKIE (23), split between the header and OPT
+        let mut forged = server_cookie.clone();
+        forged[23] ^= 1;
+        let response = handle_dns_packet1(ad, &with_cookie(&forged), CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 7);
+        assert_eq!(response[6..8], [0, 0]);
+        let opt = response.len() - 28 - 11;
+        assert_eq!(response[opt + 5], 1);
+        assert_ne!(response[response.len() - 16..], forged[8..]);
+    }
+
+    #[test]
+    fn test_padding() {
+        let listener = ListenerConfig::encrypted();
+        let ad = AppData { listener, ..app_data() };
+        let response = handle_dns_packet1(ad.clone(), QUERY, CLIENT, true).unwrap();
+        assert_eq!(response.len(), 468);
+        // Nothing to pad without an OPT record
+        let mut query = QUERY[..QUERY.len() - 11].to_vec();
+        query[11] = 0;
+        assert!(handle_dns_packet1(ad, &query, CLIENT, true).unwrap().len() < 468);
+
+        // Plaintext listeners never pad, whatever the policy
+        let ad = AppData {
+            listener: ListenerConfig { encrypted: false, ..listener },
+            ..app_data()
+        };
+        assert!(handle_dns_packet1(ad, QUERY, CLIENT, true).unwrap().len() < 468);
+    }
+
+    #[test]
+    fn test_tsig() {
+        let key = TsigKey::from_base64("transfer.example.com", TsigAlgorithm::HmacSha256, "c2VjcmV0IGtleSBmb3IgdGVzdHM=").unwrap();
+        let ad = AppData {
+            keys: Arc::new(KeyRing::new().with_key(key.clone())),
+            ..app_data()
+        };
+
+        // Signed responses end with a TSIG record of class ANY, with a full MAC and no error
+        let mut query = QUERY.to_vec();
+        sign_query(&mut query, &key, unix_time());
+        let response = handle_dns_packet1(ad.clone(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 0);
+        assert_eq!(response[6..12], [0, 1, 0, 0, 0, 2]);
+        let end = response.len();
+        assert_eq!(response[end - 40..end - 38], [0, 32]);
+        assert_eq!(response[end - 6..], [0x12, 0x34, 0, 0, 0, 0]);
+
+        // Signed too long ago: NOTAUTH with BADTIME (18), still signed
+        let mut query = QUERY.to_vec();
+        sign_query(&mut query, &key, unix_time() - 1000);
+        let response = handle_dns_packet1(ad.clone(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 9);
+        assert_eq!(response[6..8], [0, 0]);
+        assert_eq!(response[response.len() - 10..response.len() - 6], [0, 18, 0, 6]);
+
+        // Unknown keys get BADKEY (17) with no MAC
+        let response = handle_dns_packet1(app_data(), &query, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 9);
+        assert_eq!(response[response.len() - 8..], [0, 0, 0x12, 0x34, 0, 17, 0, 0]);
+
+        // The TSIG record has to come last
+        let mut query = QUERY[..QUERY.len() - 11].to_vec();
+        query[11] = 0;
+        sign_query(&mut query, &key, unix_time());
+        query[11] = 2;
+        query.extend(&QUERY[QUERY.len() - 11..]);
+        let response = handle_dns_packet1(ad, &query, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 1);
+    }
+
+    #[test]
+    fn test_kv_write_key() {
+        let key = TsigKey::from_base64("writer.example.com", TsigAlgorithm::HmacSha256, "c2VjcmV0IGtleSBmb3IgdGVzdHM=").unwrap();
+        let ad = AppData {
+            backend: Backend::Kv,
+            kv: Arc::new(Mutex::new(KvStore::default().with_write_key(key.name.clone()))),
+            keys: Arc::new(KeyRing::new().with_key(key.clone())),
+            ..app_data()
+        };
+        // foo.bar TXT stores "bar" under "foo"
+        let mut write = QUERY.to_vec();
+        write.splice(12..35, b"\x03foo\x03bar\x00".iter().copied());
+        let qtype_pos = write.len() - 15;
+        write[qtype_pos..qtype_pos + 2].copy_from_slice(&16u16.to_be_bytes());
+        let mut read = write.clone();
+        read.drain(16..20);
+
+        // Unsigned writes are refused with EDE 18 (Prohibited), and don't change anything
+        let response = handle_dns_packet1(ad.clone(), &write, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 5);
+        assert_eq!(response[6..8], [0, 0]);
+        assert!(response.ends_with(b"\x00\x12Writes must be signed with key writer.example.com"));
+        let response = handle_dns_packet1(ad.clone(), &read, CLIENT, false).unwrap();
+        assert_eq!(response[3] & 0xF, 3);
+
+        sign_query(&mut write, &key, unix_time());
+        let response = handle_dns_packet1(ad.clone(), &write, CLIENT, false).unwrap();
+        assert_eq!((response[3] & 0xF, response[7]), (0, 1));
+        // Reads don't need a signature
+        let response = handle_dns_packet1(ad, &read, CLIENT, false).unwrap();
+        assert_eq!((response[3] & 0xF, response[7]), (0, 1));
+    }
+
+    #[test]
+    fn test_no_panics() {
+        assert!(handle_dns_packet1(app_data(), QUERY, CLIENT, false).is_some());
+        for len in 0..QUERY.len() {
+            handle_dns_packet1(app_data(), &QUERY[..len], CLIENT, false);
+        }
+
+        // Set every byte of the query to a spread of values, then throw some random packets at it
+        for i in 0..QUERY.len() {
+            for value in (0..=255).step_by(5) {
+                let mut query = QUERY.to_vec();
+                query[i] = value;
+                handle_dns_packet1(app_data(), &query, CLIENT, false);
+            }
+        }
+        let mut seed: u32 = 1;
+        for _ in 0..500 {
+            let packet: Vec<u8> = (0..64)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                })
+                .collect();
+            handle_dns_packet1(app_data(), &packet, CLIENT, false);
+        }
+    }
 }
@@ -1,44 +1,43 @@
-use std::sync::{Arc, Mutex};
 use tokio::{
     net::TcpListener,
 };
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::kv::KvStore;
-use crate::nameserver::records::Records;
 use crate::servers::shared::AppData;
 
 
 pub struct TcpServer {
     socket: TcpListener,
+    app: AppData,
 }
 
 impl TcpServer {
-    pub async fn new(addr: &'static str) -> Result<Self, Box<dyn std::error::Error>> {
+    pub async fn new(addr: &str, app: AppData) -> Result<Self, Box<dyn std::error::Error>> {
         let socket = TcpListener::bind(addr).await?;
-        Ok(Self { socket })
+        Ok(Self { socket, app })
     }
 
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
         println!("TCP DNS server listening on: {:?}", self.socket.local_addr());
 
-        let mut buf = [0u8; 1024];
-
-        let records = AppData {
-            records: Arc::new(Records::predefined()),
-            kv: Arc::new(Mutex::new(KvStore::default())),
-        };
+        let records = self.app.clone();
 
         loop {
-            let (mut stream, _) = self.socket.accept().await?;
-            let size = stream.read(&mut buf).await?;
+            let (mut stream, addr) = self.socket.accept().await?;
             let records = records.clone();
             tokio::spawn(async move {
-                crate::servers::shared::handle_dns_packet(records, buf[..size].to_vec(), true, async move |bytes| {
+                // Messages over TCP are prefixed with their length (RFC 1035 4.2.2). The framing lives here so that
+                // the codec only ever sees the DNS message itself.
+                let size = stream.read_u16().await?;
+                let mut buf = vec![0u8; size as usize];
+                stream.read_exact(&mut buf).await?;
+
+                crate::servers::shared::handle_dns_packet(records, buf, addr.ip(), true, async move |bytes| {
+                    let size = u16::try_from(bytes.len()).map_err(std::io::Error::other)?;
+                    stream.write_u16(size).await?;
                     stream.write_all(&bytes).await?;
                     Ok(())
                 })
                 .await
-                .unwrap();
             });
         }
     }
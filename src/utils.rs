@@ -1,12 +1,15 @@
-use deku::bitvec::{BitVec, Msb0};
-
-pub fn bv_to_vec(mut bv: BitVec<u8, Msb0>) -> Vec<u8> {
-    bv.force_align();
-
-    assert_eq!(bv.len() % 8, 0);
+/// Uppercase hex digits, as used for generic RDATA (RFC 3597 section 5)
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
 
-    let len = bv.len() / 8;
-    let mut v = bv.into_vec();
-    v.truncate(len);
-    v
+/// Bytes from hex digits in either case
+pub fn from_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("Expected an even number of hex digits, got '{}'", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(anyhow::Error::from))
+        .collect()
 }